@@ -0,0 +1,369 @@
+use std::cmp::Ordering;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{header, Request, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::json;
+
+use crate::response;
+use crate::types::HttpResonse;
+
+// Characters that can't appear literally in a path segment of a relative link. `&` would
+// start a character reference inside the href attribute.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sort {
+    Name,
+    Size,
+    Modified,
+}
+
+impl Sort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Name => "name",
+            Sort::Size => "size",
+            Sort::Modified => "mtime",
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// What a listing shows, mirroring the settings of the view serving the files.
+pub(crate) struct Options<'a> {
+    pub root: &'a Path,  // canonical, symlinks leading out of it aren't listed
+    pub prefix: &'a str, // path a proxy stripped in front of us, only shown in the title
+    pub allow_hidden: bool,
+    pub follow_symlinks: bool,
+}
+
+async fn read_entries(dir: &Path, options: &Options<'_>) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(v) => v,
+            Err(_) => continue, // not representable in a URL we'd accept
+        };
+        if name.starts_with('.') && !options.allow_hidden {
+            continue;
+        }
+        // Only list what StaticFiles would actually serve, which rejects symlinks out of root.
+        let file_type = entry.file_type().await?;
+        if file_type.is_symlink() {
+            if !options.follow_symlinks {
+                continue;
+            }
+            match tokio::fs::canonicalize(entry.path()).await {
+                Ok(target) if target.starts_with(options.root) => (),
+                _ => continue, // dangling or outside root
+            }
+        }
+        let metadata = match tokio::fs::metadata(entry.path()).await {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// A listing of `dir`, as HTML or as JSON when asked for with `Accept: application/json` or
+/// `?format=json`. `?sort=name|size|mtime` and `?order=asc|desc` pick the order, directories
+/// always come first.
+pub(crate) async fn render<B>(
+    req: &Request<B>,
+    dir: &Path,
+    options: &Options<'_>,
+) -> anyhow::Result<HttpResonse> {
+    let mut sort = Sort::Name;
+    let mut descending = false;
+    let mut json = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("application/json") && !v.contains("text/html"))
+        .unwrap_or(false);
+    for (key, value) in url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+        match (key.as_ref(), value.as_ref()) {
+            ("sort", "size") => sort = Sort::Size,
+            ("sort", "mtime") => sort = Sort::Modified,
+            ("sort", _) => sort = Sort::Name,
+            ("order", v) => descending = v == "desc",
+            ("format", v) => json = v == "json",
+            _ => (),
+        }
+    }
+
+    let mut entries = read_entries(dir, options).await?;
+    entries.sort_by(|a, b| {
+        let ordering = match sort {
+            Sort::Name => a.name.cmp(&b.name),
+            Sort::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            Sort::Modified => a
+                .modified
+                .cmp(&b.modified)
+                .then_with(|| a.name.cmp(&b.name)),
+        };
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+
+    let path = percent_encoding::percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    let shown_path = format!("{}{}", options.prefix, path);
+    if json {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|v| {
+                json!({
+                    "name": v.name,
+                    "is_dir": v.is_dir,
+                    "size": if v.is_dir { None } else { Some(v.size) },
+                    "mtime": v
+                        .modified
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs()),
+                })
+            })
+            .collect();
+        return response::ok_json(json!({ "path": shown_path, "entries": entries }));
+    }
+
+    let header_link = |column: Sort, label: &str| {
+        // Clicking the current column again flips the order.
+        let order = if column == sort && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!(
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            column.as_str(),
+            order,
+            label
+        )
+    };
+    let title = escape_html(&shown_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n\
+         <body>\n<h1>Index of {}</h1>\n<table>\n<tr>{}{}{}</tr>\n",
+        title,
+        title,
+        header_link(Sort::Name, "Name"),
+        header_link(Sort::Size, "Size"),
+        header_link(Sort::Modified, "Last modified"),
+    );
+    if path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries.iter() {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"./{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            utf8_percent_encode(&entry.name, SEGMENT),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    response::build_response(html, StatusCode::OK, "text/html; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn defaults(root: &Path) -> Options<'_> {
+        Options {
+            root,
+            prefix: "",
+            allow_hidden: false,
+            follow_symlinks: false,
+        }
+    }
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a<b>\"c\".txt"), "12345").unwrap();
+        std::fs::write(dir.path().join("b & c.txt"), "1").unwrap();
+        std::fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::create_dir(dir.path().join(".git")).unwrap();
+        dir
+    }
+
+    async fn listing(uri: &str, accept: &str, dir: &Path, options: &Options<'_>) -> String {
+        let req = Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .body(())
+            .unwrap();
+        let r = render(&req, dir, options).await.unwrap();
+        assert_eq!(r.status(), StatusCode::OK);
+        let body = r.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+    }
+
+    #[tokio::test]
+    async fn html_escapes_names_and_links() {
+        let dir = fixture();
+        let html = listing(
+            "/files/%3Cx%3E/",
+            "text/html",
+            dir.path(),
+            &defaults(dir.path()),
+        )
+        .await;
+        assert!(html.contains("<title>Index of /files/&lt;x&gt;/</title>"));
+        assert!(html.contains(
+            "<a href=\"./a%3Cb%3E%22c%22.txt\">a&lt;b&gt;&quot;c&quot;.txt</a></td><td>5</td>"
+        ));
+        assert!(html.contains("<a href=\"./b%20%26%20c.txt\">b &amp; c.txt</a>"));
+        assert!(html.contains("<a href=\"./sub/\">sub/</a></td><td>-</td>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(!html.contains("<b>"));
+        // Directories first.
+        assert!(html.find("sub/").unwrap() < html.find("b &amp; c.txt").unwrap());
+    }
+
+    #[tokio::test]
+    async fn hides_dotfiles_unless_allowed() {
+        let dir = fixture();
+        let html = listing("/", "text/html", dir.path(), &defaults(dir.path())).await;
+        assert!(!html.contains(".env") && !html.contains(".git"));
+        assert!(!html.contains("../"));
+
+        let options = Options {
+            allow_hidden: true,
+            ..defaults(dir.path())
+        };
+        let html = listing("/", "text/html", dir.path(), &options).await;
+        assert!(html.contains("./.env\"") && html.contains("./.git/\""));
+    }
+
+    #[tokio::test]
+    async fn json_listing() {
+        let dir = fixture();
+        let options = Options {
+            prefix: "/api",
+            ..defaults(dir.path())
+        };
+        let body = listing(
+            "/files/?sort=size&order=desc",
+            "application/json",
+            dir.path(),
+            &options,
+        )
+        .await;
+        let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["path"], "/api/files/");
+        let names: Vec<&str> = v["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["sub", "a<b>\"c\".txt", "b & c.txt"]);
+        assert_eq!(v["entries"][0]["is_dir"], true);
+        assert_eq!(v["entries"][0]["size"], serde_json::Value::Null);
+        assert_eq!(v["entries"][1]["size"], 5);
+        assert!(v["entries"][1]["mtime"].is_u64());
+
+        let body = listing(
+            "/?format=json",
+            "text/html",
+            dir.path(),
+            &defaults(dir.path()),
+        )
+        .await;
+        assert!(body.starts_with('{'));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lists_only_symlinks_that_stay_in_root() {
+        use std::os::unix::fs::symlink;
+
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let dir = fixture();
+        let root = dir.path().canonicalize().unwrap();
+        symlink(outside.path().join("secret.txt"), root.join("escape.txt")).unwrap();
+        symlink(outside.path(), root.join("escape-dir")).unwrap();
+        symlink(root.join("b & c.txt"), root.join("inside.txt")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let follow = Options {
+            follow_symlinks: true,
+            ..defaults(&root)
+        };
+        let html = listing("/", "text/html", &root, &follow).await;
+        assert!(html.contains("./inside.txt\""));
+        assert!(!html.contains("escape"));
+        assert!(!html.contains("dangling"));
+
+        let html = listing("/", "text/html", &root, &defaults(&root)).await;
+        assert!(!html.contains("inside.txt"));
+    }
+}
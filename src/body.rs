@@ -0,0 +1,406 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{future, stream, Stream, TryStreamExt};
+use hyper::body::{Body, Bytes, Incoming};
+use hyper::{header, StatusCode};
+use serde::de::DeserializeOwned;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::encoding;
+use crate::response::HttpError;
+use crate::types::HttpRequest;
+
+fn too_large(limit: u64) -> anyhow::Error {
+    HttpError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request body exceeds {} bytes", limit),
+    )
+    .into()
+}
+
+// The data frames of the body, trailers are skipped.
+fn data_frames(body: &mut Incoming) -> impl Stream<Item = io::Result<Bytes>> + Send + '_ {
+    stream::poll_fn(move |cx| Pin::new(&mut *body).poll_frame(cx))
+        .try_filter_map(|frame| future::ready(Ok(frame.into_data().ok())))
+        .map_err(io::Error::other)
+}
+
+/// Reads the request body chunk by chunk as the client sends it. The next chunk is only
+/// read once the previous one was taken, so a slow consumer slows down the upload instead of
+/// buffering it. Exceeding the limit fails with a 413 [`HttpError`].
+pub struct BodyStream<'r> {
+    inner: Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + 'r>>,
+    content_encoding: Option<String>,
+    decoding: Option<String>,
+    limit: Option<u64>,
+    read: u64,
+}
+
+impl<'r> BodyStream<'r> {
+    pub fn new(req: &'r mut HttpRequest) -> Self {
+        let content_encoding = req
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        BodyStream {
+            inner: Box::pin(data_frames(req.body_mut())),
+            content_encoding,
+            decoding: None,
+            limit: None,
+            read: 0,
+        }
+    }
+
+    /// Fails as soon as more than `limit` bytes arrived. A larger `Content-Length` fails
+    /// before anything is read.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Same as [`BodyStream::new`] with the limit checked against `Content-Length` right away.
+    pub fn with_limit(req: &'r mut HttpRequest, limit: u64) -> anyhow::Result<Self> {
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if matches!(content_length, Some(len) if len > limit) {
+            return Err(too_large(limit));
+        }
+        Ok(BodyStream::new(req).limit(limit))
+    }
+
+    /// Decodes a gzip, deflate, br or zstd `Content-Encoding` while reading. The limit then
+    /// applies to the decoded bytes, so a small compressed body can't expand without bound.
+    /// Other codings fail with a 415 [`HttpError`], corrupt data with a 400.
+    pub fn decompress(mut self) -> anyhow::Result<Self> {
+        let coding = match self.content_encoding.take() {
+            Some(v) if !v.eq_ignore_ascii_case("identity") => v,
+            _ => return Ok(self),
+        };
+        let inner = std::mem::replace(&mut self.inner, Box::pin(stream::empty()));
+        let reader = encoding::decode(StreamReader::new(inner), &coding).ok_or_else(|| {
+            HttpError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported Content-Encoding {}", coding),
+            )
+        })?;
+        self.inner = Box::pin(ReaderStream::new(reader));
+        self.decoding = Some(coding);
+        Ok(self)
+    }
+
+    /// Number of body bytes read so far, after decoding.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// The next chunk, None once the body is complete.
+    pub async fn chunk(&mut self) -> anyhow::Result<Option<Bytes>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .transpose()
+    }
+
+    pub async fn to_bytes(mut self) -> anyhow::Result<Bytes> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(buf))
+    }
+
+    /// Writes the body into `file` and returns the number of bytes written.
+    pub async fn to_file(mut self, file: &mut File) -> anyhow::Result<u64> {
+        while let Some(chunk) = self.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(self.read)
+    }
+
+    /// Reads the whole body as JSON, invalid JSON fails with a 400 [`HttpError`].
+    pub async fn json<T: DeserializeOwned>(self) -> anyhow::Result<T> {
+        let bytes = self.to_bytes().await?;
+        serde_json::from_slice(&bytes).map_err(|e| {
+            HttpError::new(StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e)).into()
+        })
+    }
+
+    /// Reads the whole body as `application/x-www-form-urlencoded` pairs.
+    pub async fn form(self) -> anyhow::Result<Vec<(String, String)>> {
+        let bytes = self.to_bytes().await?;
+        Ok(url::form_urlencoded::parse(&bytes)
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect())
+    }
+
+    fn read_error(&self, e: io::Error) -> anyhow::Error {
+        // Connection errors reach the decoder wrapped in an io::Error, anything else it
+        // returns means the data isn't what the coding says.
+        let from_connection = e.get_ref().is_some_and(|v| v.is::<hyper::Error>());
+        match self.decoding {
+            Some(ref coding) if !from_connection => HttpError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Malformed {} request body", coding),
+            )
+            .into(),
+            _ => e.into(),
+        }
+    }
+}
+
+impl<'r> Stream for BodyStream<'r> {
+    type Item = anyhow::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let data = match this.inner.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(v))) => v,
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(this.read_error(e)))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        this.read += data.len() as u64;
+        if let Some(limit) = this.limit {
+            if this.read > limit {
+                return Poll::Ready(Some(Err(too_large(limit))));
+            }
+        }
+        Poll::Ready(Some(Ok(data)))
+    }
+}
+
+/// Streams the request body into a new file at `path`. The partial file is removed when the
+/// upload fails or exceeds `limit`.
+pub async fn save_to_file(
+    req: &mut HttpRequest,
+    path: impl AsRef<Path>,
+    limit: u64,
+) -> anyhow::Result<u64> {
+    let path = path.as_ref();
+    let stream = BodyStream::with_limit(req, limit)?;
+    let mut file = File::create(path).await?;
+    match stream.to_file(&mut file).await {
+        Ok(n) => Ok(n),
+        Err(e) => {
+            drop(file);
+            let _ = tokio::fs::remove_file(path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Reads a JSON request body of at most `limit` bytes after decompression.
+pub async fn read_json<T: DeserializeOwned>(
+    req: &mut HttpRequest,
+    limit: u64,
+) -> anyhow::Result<T> {
+    BodyStream::with_limit(req, limit)?
+        .decompress()?
+        .json()
+        .await
+}
+
+/// Reads a form request body of at most `limit` bytes after decompression.
+pub async fn read_form(req: &mut HttpRequest, limit: u64) -> anyhow::Result<Vec<(String, String)>> {
+    BodyStream::with_limit(req, limit)?
+        .decompress()?
+        .form()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::encoding::Encoding;
+    use crate::test_support::{read_head, request, TestServer};
+    use crate::types::HttpResonse;
+    use crate::view::View;
+    use crate::{response, SimpleApi};
+    use async_trait::async_trait;
+    use http_body_util::BodyExt;
+    use hyper::Method;
+    use regex::Regex;
+    use tokio::io::AsyncReadExt;
+
+    const LIMIT: u64 = 64 * 1024;
+
+    struct Echo;
+
+    #[async_trait]
+    impl View for Echo {
+        async fn call(
+            &self,
+            req: &mut HttpRequest,
+            _ctx: &mut Context,
+        ) -> anyhow::Result<HttpResonse> {
+            let value: serde_json::Value = read_json(req, LIMIT).await?;
+            response::ok_json(value)
+        }
+        fn methods(&self) -> Vec<Method> {
+            vec![Method::POST]
+        }
+        fn re_path(&self) -> Regex {
+            Regex::new("^/echo$").unwrap()
+        }
+    }
+
+    async fn serve() -> std::net::SocketAddr {
+        let mut app = SimpleApi::new();
+        app.add_route(Echo);
+        TestServer::start(app).await.addr
+    }
+
+    async fn compress(data: &[u8], encoding: Encoding) -> Vec<u8> {
+        let body = encoding::encode(response::full_body(data.to_vec()), encoding);
+        body.collect().await.unwrap().to_bytes().to_vec()
+    }
+
+    // Status code and body of a POST /echo.
+    async fn post(
+        addr: std::net::SocketAddr,
+        content_encoding: &str,
+        body: &[u8],
+    ) -> (u16, String) {
+        let mut head = "POST /echo HTTP/1.1\r\nContent-Type: application/json".to_string();
+        if !content_encoding.is_empty() {
+            head.push_str(&format!("\r\nContent-Encoding: {}", content_encoding));
+        }
+        let res = request(addr, head, body).await;
+        (res.status, res.text())
+    }
+
+    #[tokio::test]
+    async fn reads_plain_and_compressed_json() {
+        let addr = serve().await;
+        let json = br#"{"a":[1,2,3]}"#;
+        assert_eq!(post(addr, "", json).await.0, 200);
+        assert_eq!(post(addr, "identity", json).await.0, 200);
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let (status, body) = post(addr, encoding.name(), &compress(json, encoding).await).await;
+            assert_eq!(status, 200, "{:?}", encoding);
+            assert!(body.contains(r#"{"a":[1,2,3]}"#), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn deflate_is_zlib() {
+        let addr = serve().await;
+        let mut zlib = Vec::new();
+        async_compression::tokio::bufread::ZlibEncoder::new(&b"[true]"[..])
+            .read_to_end(&mut zlib)
+            .await
+            .unwrap();
+        let (status, body) = post(addr, "deflate", &zlib).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("[true]"));
+    }
+
+    #[tokio::test]
+    async fn limits_decoded_size() {
+        let addr = serve().await;
+        // 16 MiB of JSON that compresses to a few KiB.
+        let mut bomb = b"[\"".to_vec();
+        bomb.resize(16 * 1024 * 1024, b'a');
+        bomb.extend_from_slice(b"\"]");
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd] {
+            let compressed = compress(&bomb, encoding).await;
+            assert!((compressed.len() as u64) < LIMIT);
+            assert_eq!(post(addr, encoding.name(), &compressed).await.0, 413);
+        }
+        // Without compression the Content-Length alone is enough to refuse, before the body
+        // is even sent.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            LIMIT + 1
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        assert_eq!(read_head(&mut stream).await.status, 413);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_or_corrupt_encodings() {
+        let addr = serve().await;
+        assert_eq!(post(addr, "compress", b"[1]").await.0, 415);
+        assert_eq!(post(addr, "gzip, br", b"[1]").await.0, 415);
+        assert_eq!(post(addr, "gzip", b"[1]").await.0, 400);
+        assert_eq!(post(addr, "", b"[1").await.0, 400);
+    }
+
+    // Saves the body to `path`, answers with the number of bytes written.
+    struct Upload {
+        path: std::path::PathBuf,
+    }
+
+    #[async_trait]
+    impl View for Upload {
+        async fn call(
+            &self,
+            req: &mut HttpRequest,
+            _ctx: &mut Context,
+        ) -> anyhow::Result<HttpResonse> {
+            let n = save_to_file(req, &self.path, 16).await?;
+            response::ok_json(serde_json::json!(n))
+        }
+        fn methods(&self) -> Vec<Method> {
+            vec![Method::POST]
+        }
+        fn re_path(&self) -> Regex {
+            Regex::new("^/upload$").unwrap()
+        }
+    }
+
+    async fn serve_upload(path: &Path) -> std::net::SocketAddr {
+        let mut app = SimpleApi::new();
+        app.add_route(Upload {
+            path: path.to_path_buf(),
+        });
+        TestServer::start(app).await.addr
+    }
+
+    #[tokio::test]
+    async fn saves_body_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload.bin");
+        let addr = serve_upload(&path).await;
+        let res = request(addr, "POST /upload HTTP/1.1", b"0123456789abcdef").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json(), serde_json::json!(16));
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789abcdef");
+    }
+
+    #[tokio::test]
+    async fn removes_partial_file_over_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload.bin");
+        let addr = serve_upload(&path).await;
+
+        // Without a Content-Length the limit is only hit once the second chunk arrives, after
+        // the first one was written.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  a\r\n0123456789\r\na\r\n0123456789\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(read_head(&mut stream).await.status, 413);
+        assert!(!path.exists());
+
+        let res = request(addr, "POST /upload HTTP/1.1", &[b'x'; 17]).await;
+        assert_eq!(res.status, 413);
+        assert!(!path.exists());
+    }
+}
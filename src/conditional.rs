@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{self, HeaderMap};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+/// How `ETag`s of static files are computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EtagMode {
+    /// From size and modification time, like nginx. Free, but changes when a file is copied
+    /// without preserving mtime, e.g. on every deploy.
+    #[default]
+    Metadata,
+    /// SHA-256 of the content. Stable across deploys, but the file is read to compute it
+    /// whenever its size or mtime changed.
+    ContentHash,
+    Disabled,
+}
+
+pub(crate) fn metadata_etag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", mtime.as_nanos(), len)
+}
+
+pub(crate) fn content_etag(content: &[u8]) -> String {
+    digest_etag(Sha256::digest(content).as_slice())
+}
+
+fn digest_etag(digest: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
+
+// Same as `content_etag` of the whole file, without holding it in memory.
+async fn file_content_etag(path: &Path) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(digest_etag(hasher.finalize().as_slice()))
+}
+
+const DIGEST_CACHE_ENTRIES: usize = 4096;
+
+// Length and mtime of the file when it was hashed, and the resulting ETag.
+type DigestEntry = (u64, SystemTime, String);
+
+/// Content-hash ETags of files, so a file is only hashed again once its size or mtime
+/// changed. Files without an mtime are hashed on every call.
+#[derive(Clone, Default)]
+pub(crate) struct DigestCache {
+    entries: Arc<Mutex<HashMap<PathBuf, DigestEntry>>>,
+}
+
+impl DigestCache {
+    pub async fn content_etag(
+        &self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+    ) -> anyhow::Result<String> {
+        if let Some(modified) = modified {
+            if let Some((l, m, etag)) = self.entries.lock().unwrap().get(path) {
+                if *l == len && *m == modified {
+                    return Ok(etag.clone());
+                }
+            }
+        }
+        let etag = file_content_etag(path).await?;
+        if let Some(modified) = modified {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= DIGEST_CACHE_ENTRIES && !entries.contains_key(path) {
+                // Any entry will do, a digest is cheap to recompute compared to a whole cache.
+                if let Some(key) = entries.keys().next().cloned() {
+                    entries.remove(&key);
+                }
+            }
+            entries.insert(path.to_path_buf(), (len, modified, etag.clone()));
+        }
+        Ok(etag)
+    }
+}
+
+// HTTP dates only have second precision, Last-Modified and comparisons use the same.
+fn truncate_to_secs(t: SystemTime) -> SystemTime {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => t,
+    }
+}
+
+fn strip_weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// What identifies a version of a representation, for conditional requests.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// RFC 9110 13.2.2: If-None-Match takes precedence, If-Modified-Since is only looked at
+    /// without it.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if headers.contains_key(header::IF_NONE_MATCH) {
+            let etag = match self.etag {
+                Some(ref v) => strip_weak(v),
+                None => return false,
+            };
+            return headers
+                .get_all(header::IF_NONE_MATCH)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(|v| v.trim())
+                .any(|v| v == "*" || strip_weak(v) == etag);
+        }
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok());
+        match (since, self.last_modified) {
+            (Some(since), Some(modified)) => truncate_to_secs(modified) <= since,
+            _ => false,
+        }
+    }
+
+    /// `If-Range` holds either an entity tag, compared strongly, or the exact Last-Modified date.
+    pub fn if_range_matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') || value.starts_with("W/") {
+            return !value.starts_with("W/") && self.etag.as_deref() == Some(value);
+        }
+        match (httpdate::parse_http_date(value), self.last_modified) {
+            (Ok(date), Some(modified)) => truncate_to_secs(modified) == date,
+            _ => false,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        if let Some(ref etag) = self.etag {
+            headers.insert(header::ETAG, etag.parse()?);
+        }
+        if let Some(modified) = self.last_modified {
+            let date = httpdate::fmt_http_date(truncate_to_secs(modified));
+            headers.insert(header::LAST_MODIFIED, date.parse()?);
+        }
+        Ok(())
+    }
+}
+
+enum Matcher {
+    Extension(String),
+    Pattern(Regex),
+}
+
+/// `Cache-Control` values picked by file path, the first matching rule wins.
+#[derive(Default)]
+pub(crate) struct CacheRules {
+    rules: Vec<(Matcher, String)>,
+    default: Option<String>,
+}
+
+impl CacheRules {
+    pub fn add_extension(&mut self, extension: &str, value: &str) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.rules
+            .push((Matcher::Extension(extension), value.to_string()));
+    }
+
+    pub fn add_pattern(&mut self, pattern: Regex, value: &str) {
+        self.rules
+            .push((Matcher::Pattern(pattern), value.to_string()));
+    }
+
+    pub fn set_default(&mut self, value: &str) {
+        self.default = Some(value.to_string());
+    }
+
+    /// `path` is relative to the served root, with `/` separators.
+    pub fn get(&self, path: &str) -> Option<&str> {
+        let extension = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        self.rules
+            .iter()
+            .find(|(matcher, _)| match matcher {
+                Matcher::Extension(ext) => extension.as_deref() == Some(ext.as_str()),
+                Matcher::Pattern(re) => re.is_match(path),
+            })
+            .map(|(_, value)| value.as_str())
+            .or(self.default.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        map
+    }
+
+    // 2015-10-21 07:28:00.5 UTC
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_445_412_480_500)
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some(modified()),
+        }
+    }
+
+    #[test]
+    fn if_none_match() {
+        let v = validators();
+        assert!(v.is_not_modified(&headers(&[("if-none-match", "\"abc\"")])));
+        assert!(v.is_not_modified(&headers(&[("if-none-match", "W/\"abc\"")])));
+        assert!(v.is_not_modified(&headers(&[("if-none-match", "\"x\", \"abc\"")])));
+        assert!(v.is_not_modified(&headers(&[
+            ("if-none-match", "\"x\""),
+            ("if-none-match", "\"abc\""),
+        ])));
+        assert!(v.is_not_modified(&headers(&[("if-none-match", "*")])));
+        assert!(!v.is_not_modified(&headers(&[("if-none-match", "\"abcd\"")])));
+        assert!(!v.is_not_modified(&headers(&[("if-none-match", "abc")])));
+
+        let no_etag = Validators {
+            etag: None,
+            ..validators()
+        };
+        assert!(!no_etag.is_not_modified(&headers(&[("if-none-match", "*")])));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let v = validators();
+        let h = headers(&[
+            ("if-none-match", "\"old\""),
+            ("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        assert!(!v.is_not_modified(&h));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let v = validators();
+        // The sub-second part of the mtime doesn't count.
+        assert!(v.is_not_modified(&headers(&[(
+            "if-modified-since",
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        )])));
+        assert!(v.is_not_modified(&headers(&[(
+            "if-modified-since",
+            "Thu, 22 Oct 2015 07:28:00 GMT"
+        )])));
+        assert!(!v.is_not_modified(&headers(&[(
+            "if-modified-since",
+            "Wed, 21 Oct 2015 07:27:59 GMT"
+        )])));
+        assert!(!v.is_not_modified(&headers(&[("if-modified-since", "yesterday")])));
+        assert!(!v.is_not_modified(&HeaderMap::new()));
+
+        let no_mtime = Validators {
+            last_modified: None,
+            ..validators()
+        };
+        assert!(!no_mtime.is_not_modified(&headers(&[(
+            "if-modified-since",
+            "Thu, 22 Oct 2015 07:28:00 GMT"
+        )])));
+    }
+
+    #[test]
+    fn if_range() {
+        let v = validators();
+        assert!(v.if_range_matches("\"abc\""));
+        assert!(v.if_range_matches(" \"abc\" "));
+        assert!(!v.if_range_matches("W/\"abc\""));
+        assert!(!v.if_range_matches("\"abcd\""));
+        assert!(v.if_range_matches("Wed, 21 Oct 2015 07:28:00 GMT"));
+        // Only an exact date match counts, not "modified since".
+        assert!(!v.if_range_matches("Thu, 22 Oct 2015 07:28:00 GMT"));
+        assert!(!v.if_range_matches("garbage"));
+
+        let empty = Validators::default();
+        assert!(!empty.if_range_matches("\"abc\""));
+        assert!(!empty.if_range_matches("Wed, 21 Oct 2015 07:28:00 GMT"));
+    }
+
+    #[test]
+    fn apply_sets_headers() {
+        let mut h = HeaderMap::new();
+        validators().apply(&mut h).unwrap();
+        assert_eq!(h[header::ETAG], "\"abc\"");
+        assert_eq!(h[header::LAST_MODIFIED], "Wed, 21 Oct 2015 07:28:00 GMT");
+    }
+
+    #[test]
+    fn metadata_etag_changes_with_size_and_mtime() {
+        let etag = metadata_etag(10, Some(modified()));
+        assert_ne!(etag, metadata_etag(11, Some(modified())));
+        assert_ne!(
+            etag,
+            metadata_etag(10, Some(modified() + Duration::from_millis(1)))
+        );
+        assert_eq!(metadata_etag(10, None), "\"0-a\"");
+    }
+
+    #[tokio::test]
+    async fn digest_cache_rehashes_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let cache = DigestCache::default();
+
+        let etag = cache
+            .content_etag(&path, 5, Some(modified()))
+            .await
+            .unwrap();
+        assert_eq!(etag, content_etag(b"hello"));
+
+        // Same size and mtime: the stored digest is used without reading the file.
+        std::fs::write(&path, "HELLO").unwrap();
+        let cached = cache
+            .content_etag(&path, 5, Some(modified()))
+            .await
+            .unwrap();
+        assert_eq!(cached, etag);
+
+        let later = modified() + Duration::from_secs(1);
+        let changed = cache.content_etag(&path, 5, Some(later)).await.unwrap();
+        assert_eq!(changed, content_etag(b"HELLO"));
+
+        // Without an mtime nothing is cached.
+        std::fs::write(&path, "howdy").unwrap();
+        let uncached = cache.content_etag(&path, 5, None).await.unwrap();
+        assert_eq!(uncached, content_etag(b"howdy"));
+    }
+
+    #[tokio::test]
+    async fn digest_of_large_file_matches_in_memory_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.bin");
+        let content = (0..200_000u32).map(|v| v as u8).collect::<Vec<u8>>();
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(
+            file_content_etag(&path).await.unwrap(),
+            content_etag(&content)
+        );
+    }
+}
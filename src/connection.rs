@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use crate::proxy_protocol::ProxyInfo;
+use crate::tls::TlsInfo;
+
+/// Metadata about the connection a request arrived on, shared by all requests on it.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionInfo {
+    pub id: u64, // unique within the process, 0 when the service wasn't created by the server
+    pub peer_addr: Option<SocketAddr>, // None on unix sockets
+    pub local_addr: Option<SocketAddr>, // None on unix sockets
+    pub tls: Option<TlsInfo>, // None for plain-text connections
+    pub proxy: Option<ProxyInfo>, // Only on listeners with the PROXY protocol enabled
+}
+
+impl ConnectionInfo {
+    /// The source address reported by the PROXY protocol if there is one, the peer otherwise.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.proxy
+            .as_ref()
+            .and_then(|v| v.source)
+            .or(self.peer_addr)
+    }
+}
@@ -1,8 +1,10 @@
 use std::any::Any;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use crate::{
+    connection::ConnectionInfo,
     session::{Session, SessionProvider},
     types::{State, ViewPathArgs},
 };
@@ -23,12 +25,29 @@ impl AnyMap {
     }
 }
 
+// any_map keys of values other parts of the framework put there.
+pub(crate) const CSRF_TOKEN_KEY: &str = "csrf_token";
+pub(crate) const SUBMITTED_FORM_KEY: &str = "submitted_form";
+pub(crate) const CSP_NONCE_KEY: &str = "csp_nonce";
+
+/// The client as seen by the first trusted proxy, filled in by `ForwardedMiddleware`.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardedInfo {
+    pub client_ip: Option<IpAddr>,
+    pub scheme: Option<String>,
+    pub host: Option<String>,
+    pub prefix: Option<String>, // path prefix the proxy strips, e.g. "/api"
+}
+
 pub struct Context {
     pub any_map: AnyMap, // like flask g
     pub session: Option<Box<dyn Session>>,
     pub session_provider: Option<Arc<dyn SessionProvider>>,
     pub state: State,
     pub view_args: Option<ViewPathArgs>, // 只有在route匹配失败时，才会为None。
+    pub conn: Arc<ConnectionInfo>,
+    pub request_seq: u64, // 1 for the first request on the connection
+    pub forwarded: Option<ForwardedInfo>,
 }
 
 impl Context {
@@ -36,6 +55,8 @@ impl Context {
         session_provider: Option<Arc<dyn SessionProvider>>,
         state: State,
         view_args: Option<ViewPathArgs>,
+        conn: Arc<ConnectionInfo>,
+        request_seq: u64,
     ) -> Self {
         Context {
             any_map: AnyMap(HashMap::new()),
@@ -43,6 +64,9 @@ impl Context {
             session_provider,
             state,
             view_args,
+            conn,
+            request_seq,
+            forwarded: None,
         }
     }
 
@@ -52,4 +76,61 @@ impl Context {
             .downcast::<T>()
             .map_err(|_| anyhow::anyhow!("cast failed"))
     }
+
+    /// Client IP taking trusted proxies into account, see [`ConnectionInfo::client_addr`] for
+    /// what is used when there is no forwarding information.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded
+            .as_ref()
+            .and_then(|v| v.client_ip)
+            .or_else(|| self.conn.client_addr().map(|v| v.ip()))
+    }
+
+    /// "https" or "http", as the client used it.
+    pub fn scheme(&self) -> &str {
+        match self.forwarded.as_ref().and_then(|v| v.scheme.as_deref()) {
+            Some(v) => v,
+            None if self.conn.tls.is_some() => "https",
+            None => "http",
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.scheme() == "https"
+    }
+
+    /// Host the client connected to, only known once `ForwardedMiddleware` ran.
+    pub fn host(&self) -> Option<&str> {
+        self.forwarded.as_ref().and_then(|v| v.host.as_deref())
+    }
+
+    pub fn path_prefix(&self) -> &str {
+        self.forwarded
+            .as_ref()
+            .and_then(|v| v.prefix.as_deref())
+            .unwrap_or("")
+    }
+
+    /// The token forms and scripts must send back, set by `CsrfMiddleware`.
+    pub fn csrf_token(&self) -> Option<&str> {
+        self.any_map
+            .get::<String>(CSRF_TOKEN_KEY)
+            .map(|v| v.as_str())
+    }
+
+    /// The url-encoded form `CsrfMiddleware` read to find the token. The request body is
+    /// consumed then, views get the fields from here instead.
+    pub fn submitted_form(&self) -> Option<&[(String, String)]> {
+        self.any_map
+            .get::<Vec<(String, String)>>(SUBMITTED_FORM_KEY)
+            .map(|v| v.as_slice())
+    }
+
+    /// The nonce of this response's Content-Security-Policy, set by `SecurityHeadersMiddleware`
+    /// when the policy has a `{nonce}` placeholder. Inline scripts need `nonce="..."` with it.
+    pub fn csp_nonce(&self) -> Option<&str> {
+        self.any_map
+            .get::<String>(CSP_NONCE_KEY)
+            .map(|v| v.as_str())
+    }
 }
@@ -0,0 +1,483 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use hyper::{header, Method};
+use regex::Regex;
+
+use crate::{
+    conditional::{self, CacheRules, Validators},
+    context::Context,
+    encoding::{self, Encoding},
+    types::{HttpRequest, HttpResonse},
+    view::View,
+    views::{self, Content},
+};
+
+/// A file baked into the binary, see [`EmbedBuilder`] for how the table is generated.
+pub struct EmbeddedFile {
+    pub path: &'static str, // relative to the embedded directory, `/` separated
+    pub content: &'static [u8],
+    pub mime: &'static str,
+    pub etag: &'static str,
+    pub modified: Option<u64>, // seconds since the epoch
+    pub brotli: Option<&'static [u8]>,
+    pub gzip: Option<&'static [u8]>,
+}
+
+/// Like [`views::StaticFiles`], but serves [`EmbeddedFile`]s instead of a directory on disk.
+/// Files are served without copying them.
+pub struct EmbeddedFiles {
+    files: HashMap<&'static str, &'static EmbeddedFile>,
+    dirs: HashSet<String>,
+    re_path: Regex,
+    cache_rules: CacheRules,
+    index_files: Vec<String>,
+    precompressed: bool,
+    spa_fallback: Option<String>,
+}
+
+impl EmbeddedFiles {
+    pub fn new(files: &'static [EmbeddedFile], re_path: Regex) -> Self {
+        let mut dirs = HashSet::new();
+        for file in files.iter() {
+            let mut path = file.path;
+            while let Some((dir, _)) = path.rsplit_once('/') {
+                dirs.insert(dir.to_string());
+                path = dir;
+            }
+        }
+        EmbeddedFiles {
+            files: files.iter().map(|v| (v.path, v)).collect(),
+            dirs,
+            re_path,
+            cache_rules: CacheRules::default(),
+            index_files: vec!["index.html".to_string()],
+            precompressed: true,
+            spa_fallback: None,
+        }
+    }
+
+    /// See [`views::StaticFiles::cache_control`].
+    pub fn cache_control(mut self, pattern: Regex, value: &str) -> Self {
+        self.cache_rules.add_pattern(pattern, value);
+        self
+    }
+
+    pub fn cache_control_extension(mut self, extension: &str, value: &str) -> Self {
+        self.cache_rules.add_extension(extension, value);
+        self
+    }
+
+    pub fn default_cache_control(mut self, value: &str) -> Self {
+        self.cache_rules.set_default(value);
+        self
+    }
+
+    pub fn index_files(mut self, names: &[&str]) -> Self {
+        self.index_files = names.iter().map(|v| v.to_string()).collect();
+        self
+    }
+
+    /// Sends the embedded brotli or gzip variant when the client accepts it, enabled by
+    /// default. Only files that had a `.br` or `.gz` sibling when embedding have variants.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// See [`views::StaticFiles::spa_fallback`].
+    pub fn spa_fallback(mut self, index: &str) -> Self {
+        self.spa_fallback = Some(index.trim_start_matches('/').to_string());
+        self
+    }
+
+    fn find_index(&self, dir: &str) -> Option<&'static EmbeddedFile> {
+        self.index_files.iter().find_map(|name| {
+            let path = match dir {
+                "" => name.clone(),
+                dir => format!("{}/{}", dir, name),
+            };
+            self.files.get(path.as_str()).copied()
+        })
+    }
+
+    async fn serve(
+        &self,
+        req: &HttpRequest,
+        file: &'static EmbeddedFile,
+    ) -> anyhow::Result<HttpResonse> {
+        let mut variants = Vec::new();
+        if let Some(v) = file.brotli {
+            variants.push((Encoding::Brotli, v));
+        }
+        if let Some(v) = file.gzip {
+            variants.push((Encoding::Gzip, v));
+        }
+        let encodings: Vec<Encoding> = variants.iter().map(|(v, _)| *v).collect();
+        let encoding = match self.precompressed {
+            true => encoding::preferred(req, &encodings),
+            false => None,
+        };
+        let (content, etag) = match encoding {
+            Some(encoding) => {
+                let (_, content) = variants.iter().find(|(v, _)| *v == encoding).unwrap();
+                let etag = format!("{}-{}\"", file.etag.trim_end_matches('"'), encoding.name());
+                (*content, etag)
+            }
+            None => (file.content, file.etag.to_string()),
+        };
+        let validators = Validators {
+            etag: Some(etag),
+            last_modified: file.modified.map(|v| UNIX_EPOCH + Duration::from_secs(v)),
+        };
+        let mut r = views::representation_response(
+            req,
+            Content::Memory(Bytes::from_static(content)),
+            content.len() as u64,
+            &validators,
+            file.mime,
+        )
+        .await?;
+        if let Some(value) = self.cache_rules.get(file.path) {
+            r.headers_mut()
+                .insert(header::CACHE_CONTROL, value.parse()?);
+        }
+        if self.precompressed && !variants.is_empty() {
+            r.headers_mut()
+                .insert(header::VARY, "accept-encoding".parse()?);
+        }
+        if let Some(encoding) = encoding {
+            r.headers_mut()
+                .insert(header::CONTENT_ENCODING, encoding.name().parse()?);
+        }
+        Ok(r)
+    }
+}
+
+#[async_trait]
+impl View for EmbeddedFiles {
+    fn re_path(&self) -> Regex {
+        self.re_path.clone()
+    }
+    fn methods(&self) -> Vec<Method> {
+        vec![Method::GET]
+    }
+    async fn call(&self, req: &mut HttpRequest, ctx: &mut Context) -> anyhow::Result<HttpResonse> {
+        let file_path = ctx
+            .view_args
+            .as_ref()
+            .ok_or(anyhow!("no view_args"))?
+            .get("file_path")
+            .ok_or(anyhow!("no file_path"))?;
+        // Hidden files are only there when they were embedded on purpose.
+        let relative = match views::sanitize_path(file_path, true) {
+            Some(v) => views::relative_string(&v),
+            None => return views::not_found(),
+        };
+        if let Some(file) = self.files.get(relative.as_str()) {
+            return self.serve(req, file).await;
+        }
+        if relative.is_empty() || self.dirs.contains(&relative) {
+            if !req.uri().path().ends_with('/') {
+                return views::redirect_to_directory(req, ctx.path_prefix());
+            }
+            return match self.find_index(&relative) {
+                Some(file) => self.serve(req, file).await,
+                None => views::not_found(),
+            };
+        }
+        let fallback = match self.spa_fallback {
+            Some(ref v) if views::is_client_route(req, file_path) => self.files.get(v.as_str()),
+            _ => None,
+        };
+        match fallback {
+            Some(file) => self.serve(req, file).await,
+            None => views::not_found(),
+        }
+    }
+}
+
+/// Generates the [`EmbeddedFile`] table for a directory from a build script. `app.js.br` and
+/// `app.js.gz` next to `app.js` are embedded as its precompressed variants.
+///
+/// ```ignore
+/// // build.rs
+/// let out = std::path::Path::new(&std::env::var("OUT_DIR")?).join("assets.rs");
+/// simple_api::embed::EmbedBuilder::new("assets").write(out)?;
+///
+/// // main.rs
+/// static ASSETS: &[EmbeddedFile] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+/// app.add_route(EmbeddedFiles::new(ASSETS, Regex::new("^/static/(?P<file_path>.*)$")?));
+/// ```
+pub struct EmbedBuilder {
+    dir: PathBuf,
+    include_hidden: bool,
+}
+
+impl EmbedBuilder {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        EmbedBuilder {
+            dir: dir.as_ref().to_path_buf(),
+            include_hidden: false,
+        }
+    }
+
+    /// Embeds files and directories whose name starts with a dot, skipped by default.
+    pub fn include_hidden(mut self, enabled: bool) -> Self {
+        self.include_hidden = enabled;
+        self
+    }
+
+    /// Writes the table to `out` and tells cargo to rerun the build script when the directory
+    /// changes.
+    pub fn write(&self, out: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dir = self.dir.canonicalize()?;
+        let mut files = Vec::new();
+        self.walk(&dir, &mut files)?;
+        files.sort();
+        let known: HashSet<&PathBuf> = files.iter().collect();
+
+        let mut code = format!(
+            "// Generated by simple_api::embed::EmbedBuilder from {:?}, do not edit.\n&[\n",
+            dir
+        );
+        for path in files.iter() {
+            let name = path.to_string_lossy();
+            // Siblings of an embedded file are embedded as its variants instead.
+            let is_variant = Encoding::PRECOMPRESSED.iter().any(|v| {
+                name.strip_suffix(&format!(".{}", v.extension()))
+                    .is_some_and(|original| known.contains(&PathBuf::from(original)))
+            });
+            if is_variant {
+                continue;
+            }
+            let relative = path.strip_prefix(&dir)?;
+            let relative = relative
+                .to_str()
+                .ok_or(anyhow!("{} is not valid UTF-8", relative.display()))?;
+            let relative = views::relative_string(Path::new(relative));
+            let metadata = std::fs::metadata(path)?;
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|v| v.duration_since(UNIX_EPOCH).ok())
+                .map(|v| v.as_secs());
+            let variant = |encoding: Encoding| {
+                let variant = format!("{}.{}", name, encoding.extension());
+                match known.contains(&PathBuf::from(&variant)) {
+                    true => format!("Some(include_bytes!({:?}))", variant),
+                    false => "None".to_string(),
+                }
+            };
+            writeln!(code, "    simple_api::embed::EmbeddedFile {{")?;
+            writeln!(code, "        path: {:?},", relative)?;
+            writeln!(code, "        content: include_bytes!({:?}),", name)?;
+            writeln!(
+                code,
+                "        mime: {:?},",
+                mime_guess::from_path(path).first_or_octet_stream().as_ref()
+            )?;
+            writeln!(
+                code,
+                "        etag: {:?},",
+                conditional::content_etag(&std::fs::read(path)?)
+            )?;
+            writeln!(code, "        modified: {:?},", modified)?;
+            writeln!(code, "        brotli: {},", variant(Encoding::Brotli))?;
+            writeln!(code, "        gzip: {},", variant(Encoding::Gzip))?;
+            writeln!(code, "    }},")?;
+        }
+        code.push_str("]\n");
+        std::fs::write(out, code)?;
+        Ok(())
+    }
+
+    fn walk(&self, dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+        println!("cargo:rerun-if-changed={}", dir.display());
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden && !self.include_hidden {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                self.walk(&path, files)?;
+            } else if path.is_file() {
+                println!("cargo:rerun-if-changed={}", path.display());
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request, RawResponse, TestServer};
+
+    static FILES: &[EmbeddedFile] = &[
+        EmbeddedFile {
+            path: "index.html",
+            content: b"index",
+            mime: "text/html",
+            etag: "\"index\"",
+            modified: Some(1_700_000_000),
+            brotli: None,
+            gzip: None,
+        },
+        EmbeddedFile {
+            path: "docs/index.html",
+            content: b"docs",
+            mime: "text/html",
+            etag: "\"docs\"",
+            modified: None,
+            brotli: None,
+            gzip: None,
+        },
+        EmbeddedFile {
+            path: "js/app.js",
+            content: b"app",
+            mime: "text/javascript",
+            etag: "\"app\"",
+            modified: None,
+            brotli: Some(b"app-br"),
+            gzip: Some(b"app-gz"),
+        },
+    ];
+
+    async fn serve(files: EmbeddedFiles) -> std::net::SocketAddr {
+        let mut app = crate::SimpleApi::new();
+        app.add_route(files);
+        TestServer::start(app).await.addr
+    }
+
+    fn files() -> EmbeddedFiles {
+        EmbeddedFiles::new(FILES, Regex::new("^/static/(?P<file_path>.*)$").unwrap())
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str, headers: &str) -> RawResponse {
+        request(addr, format!("GET {} HTTP/1.1{}", path, headers), b"").await
+    }
+
+    #[tokio::test]
+    async fn serves_files_and_variants() {
+        let addr = serve(files()).await;
+        let res = get(addr, "/static/js/app.js", "").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"app");
+        assert_eq!(res.header("content-type"), Some("text/javascript"));
+        assert_eq!(res.header("etag"), Some("\"app\""));
+        assert_eq!(res.header("vary"), Some("accept-encoding"));
+
+        let res = get(addr, "/static/js/app.js", "\r\nAccept-Encoding: gzip, br").await;
+        assert_eq!(res.body, b"app-br");
+        assert_eq!(res.header("content-encoding"), Some("br"));
+        assert_eq!(res.header("content-type"), Some("text/javascript"));
+        assert_eq!(res.header("etag"), Some("\"app-br\""));
+        let res = get(addr, "/static/js/app.js", "\r\nAccept-Encoding: gzip").await;
+        assert_eq!(res.body, b"app-gz");
+
+        // Files without variants don't vary.
+        let res = get(addr, "/static/index.html", "\r\nAccept-Encoding: br").await;
+        assert_eq!(res.body, b"index");
+        assert_eq!(res.header("vary"), None);
+        assert!(res.header("last-modified").is_some());
+
+        let addr = serve(files().precompressed(false)).await;
+        let res = get(addr, "/static/js/app.js", "\r\nAccept-Encoding: br").await;
+        assert_eq!(res.body, b"app");
+        assert_eq!(res.header("vary"), None);
+    }
+
+    #[tokio::test]
+    async fn answers_conditional_requests() {
+        let addr = serve(files()).await;
+        let res = get(addr, "/static/js/app.js", "\r\nIf-None-Match: \"app\"").await;
+        assert_eq!(res.status, 304);
+        assert!(res.body.is_empty());
+        let headers = "\r\nAccept-Encoding: br\r\nIf-None-Match: \"app-br\"";
+        assert_eq!(get(addr, "/static/js/app.js", headers).await.status, 304);
+        let headers = "\r\nAccept-Encoding: br\r\nIf-None-Match: \"app\"";
+        assert_eq!(get(addr, "/static/js/app.js", headers).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn redirects_directories_to_their_index() {
+        let addr = serve(files()).await;
+        let res = get(addr, "/static/docs?x=1", "").await;
+        assert_eq!(res.status, 301);
+        assert_eq!(res.header("location"), Some("/static/docs/?x=1"));
+        assert_eq!(get(addr, "/static/docs/", "").await.body, b"docs");
+        assert_eq!(get(addr, "/static/", "").await.body, b"index");
+        // A directory without an index, and paths that aren't embedded.
+        assert_eq!(get(addr, "/static/js/", "").await.status, 404);
+        assert_eq!(get(addr, "/static/missing.js", "").await.status, 404);
+        assert_eq!(get(addr, "/static/../Cargo.toml", "").await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn spa_fallback() {
+        let addr = serve(files().spa_fallback("/index.html")).await;
+        let html = "\r\nAccept: text/html";
+        let res = get(addr, "/static/app/settings", html).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"index");
+        assert_eq!(get(addr, "/static/app.js", html).await.status, 404);
+        assert_eq!(get(addr, "/static/app/settings", "").await.status, 404);
+    }
+
+    #[test]
+    fn generates_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let assets = dir.path().join("assets");
+        std::fs::create_dir_all(assets.join("css")).unwrap();
+        std::fs::write(assets.join("app.js"), "app").unwrap();
+        std::fs::write(assets.join("app.js.br"), "br").unwrap();
+        std::fs::write(assets.join("css/site.css"), "css").unwrap();
+        std::fs::write(assets.join("notes.gz"), "not a variant").unwrap();
+        std::fs::write(assets.join(".hidden"), "secret").unwrap();
+        let out = dir.path().join("assets.rs");
+
+        EmbedBuilder::new(&assets).write(&out).unwrap();
+        let code = std::fs::read_to_string(&out).unwrap();
+        let paths: Vec<&str> = code
+            .lines()
+            .filter_map(|v| v.trim().strip_prefix("path: "))
+            .collect();
+        assert_eq!(paths, ["\"app.js\",", "\"css/site.css\",", "\"notes.gz\","]);
+        assert!(!code.contains(".hidden"));
+
+        let root = assets.canonicalize().unwrap();
+        let entry = |path: &str| {
+            let start = code.find(&format!("path: {:?}", path)).unwrap();
+            let end = code[start..].find("},").unwrap() + start;
+            code[start..end].to_string()
+        };
+        let app = entry("app.js");
+        let br = root.join("app.js.br").to_string_lossy().to_string();
+        assert!(app.contains(&format!("brotli: Some(include_bytes!({:?}))", br)));
+        assert!(app.contains("gzip: None"));
+        assert!(app.contains(&format!("etag: {:?}", conditional::content_etag(b"app"))));
+        let js = mime_guess::from_path("app.js").first_or_octet_stream();
+        assert!(app.contains(&format!("mime: {:?}", js.as_ref())));
+        let css = entry("css/site.css");
+        let site = root.join("css/site.css").to_string_lossy().to_string();
+        assert!(css.contains(&format!("content: include_bytes!({:?})", site)));
+        assert!(css.contains("brotli: None"));
+
+        EmbedBuilder::new(&assets)
+            .include_hidden(true)
+            .write(&out)
+            .unwrap();
+        assert!(std::fs::read_to_string(&out)
+            .unwrap()
+            .contains("path: \".hidden\""));
+    }
+}
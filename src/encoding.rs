@@ -0,0 +1,231 @@
+use std::io;
+use std::pin::Pin;
+
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZstdDecoder, ZstdEncoder,
+};
+use async_compression::Level;
+use futures_util::{future, stream, TryStreamExt};
+use hyper::body::Body;
+use hyper::{header, Request};
+use tokio::io::{AsyncBufRead, AsyncRead};
+use tokio_util::io::StreamReader;
+
+use crate::response;
+use crate::types::HttpBody;
+
+/// A content coding the server can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The encodings static files can have precompressed siblings for.
+    pub const PRECOMPRESSED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    /// The name used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// The file extension of a precompressed sibling, e.g. `app.js.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+            Encoding::Zstd => "zst",
+        }
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        match self {
+            Encoding::Brotli => token.eq_ignore_ascii_case("br"),
+            Encoding::Gzip => {
+                token.eq_ignore_ascii_case("gzip") || token.eq_ignore_ascii_case("x-gzip")
+            }
+            Encoding::Zstd => token.eq_ignore_ascii_case("zstd"),
+        }
+    }
+}
+
+// (coding, q) pairs of Accept-Encoding, q defaults to 1.
+fn accepted<B>(req: &Request<B>) -> Vec<(String, f32)> {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|v| v.trim().strip_prefix("q="))
+                .find_map(|v| v.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding.to_string(), q))
+        })
+        .collect()
+}
+
+/// The encoding out of `available` the client prefers, None when it accepts none of them.
+/// Codings the client doesn't list are only acceptable through `*`. Ties go to the one that
+/// comes first in `available`.
+pub(crate) fn preferred<B>(req: &Request<B>, available: &[Encoding]) -> Option<Encoding> {
+    let accepted = accepted(req);
+    let wildcard = accepted
+        .iter()
+        .find(|(coding, _)| coding == "*")
+        .map(|(_, q)| *q);
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let q = accepted
+            .iter()
+            .find(|(coding, _)| encoding.matches(coding))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        let better = match best {
+            Some((_, best_q)) => q > best_q,
+            None => true,
+        };
+        if q > 0.0 && better {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// The data frames of `body` as a reader, trailers are dropped.
+fn body_reader(mut body: HttpBody) -> impl AsyncBufRead + Send {
+    let frames = stream::poll_fn(move |cx| Pin::new(&mut body).poll_frame(cx));
+    let data = frames
+        .try_filter_map(|frame| future::ready(Ok(frame.into_data().ok())))
+        .map_err(io::Error::other);
+    StreamReader::new(data)
+}
+
+/// Compresses `body` as it is sent.
+pub(crate) fn encode(body: HttpBody, encoding: Encoding) -> HttpBody {
+    let reader = body_reader(body);
+    match encoding {
+        // The default quality 11 is meant for precompressing, far too slow per response.
+        Encoding::Brotli => {
+            response::reader_body(BrotliEncoder::with_quality(reader, Level::Precise(4)))
+        }
+        Encoding::Gzip => response::reader_body(GzipEncoder::new(reader)),
+        Encoding::Zstd => response::reader_body(ZstdEncoder::new(reader)),
+    }
+}
+
+/// Undoes the content coding `name` of a request body as it is read, None for codings that
+/// aren't supported.
+pub(crate) fn decode<'a>(
+    reader: impl AsyncBufRead + Send + 'a,
+    name: &str,
+) -> Option<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+    let name = name.to_ascii_lowercase();
+    let reader: Pin<Box<dyn AsyncRead + Send + 'a>> = match name.as_str() {
+        "gzip" | "x-gzip" => Box::pin(GzipDecoder::new(reader)),
+        // HTTP's "deflate" is the zlib format, not a raw deflate stream.
+        "deflate" => Box::pin(ZlibDecoder::new(reader)),
+        "br" => Box::pin(BrotliDecoder::new(reader)),
+        "zstd" => Box::pin(ZstdDecoder::new(reader)),
+        _ => return None,
+    };
+    Some(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncReadExt;
+
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    fn pick(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+        let req = Request::get("/")
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .unwrap();
+        preferred(&req, available)
+    }
+
+    #[test]
+    fn highest_q_wins() {
+        assert_eq!(pick("gzip", &ALL), Some(Encoding::Gzip));
+        assert_eq!(pick("gzip;q=1.0, br;q=0.5", &ALL), Some(Encoding::Gzip));
+        assert_eq!(pick("gzip; q=0.2, zstd ;q=0.9", &ALL), Some(Encoding::Zstd));
+        assert_eq!(
+            pick("GZIP, x-gzip", &[Encoding::Gzip]),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(pick("x-gzip", &ALL), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn ties_go_to_server_order() {
+        assert_eq!(pick("gzip, br, zstd", &ALL), Some(Encoding::Brotli));
+        assert_eq!(pick("gzip, zstd", &ALL), Some(Encoding::Zstd));
+        assert_eq!(
+            pick("br, gzip", &[Encoding::Gzip, Encoding::Brotli]),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn q_zero_refuses() {
+        assert_eq!(pick("br;q=0, gzip", &ALL), Some(Encoding::Gzip));
+        assert_eq!(pick("br;q=0", &ALL), None);
+        assert_eq!(pick("gzip;q=0.000", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(pick("*", &ALL), Some(Encoding::Brotli));
+        assert_eq!(pick("*;q=0.5, gzip;q=0.8", &ALL), Some(Encoding::Gzip));
+        assert_eq!(pick("*, br;q=0", &ALL), Some(Encoding::Zstd));
+        assert_eq!(pick("*;q=0", &ALL), None);
+        assert_eq!(pick("*;q=0, gzip", &ALL), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn nothing_acceptable() {
+        assert_eq!(pick("identity", &ALL), None);
+        assert_eq!(pick("", &ALL), None);
+        assert_eq!(pick("deflate, compress", &ALL), None);
+        assert_eq!(pick("br", &[Encoding::Gzip]), None);
+        assert_eq!(preferred(&Request::get("/").body(()).unwrap(), &ALL), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let data = "hello world ".repeat(1000);
+        for encoding in ALL {
+            let body = encode(response::full_body(data.clone()), encoding);
+            let compressed = body.collect().await.unwrap().to_bytes();
+            assert!(compressed.len() < data.len(), "{:?}", encoding);
+
+            let mut decoded = String::new();
+            decode(compressed.as_ref(), encoding.name())
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .await
+                .unwrap();
+            assert_eq!(decoded, data, "{:?}", encoding);
+        }
+        assert!(decode(&b""[..], "compress").is_none());
+        assert!(decode(&b""[..], "identity").is_none());
+        assert!(decode(&b""[..], "GZIP").is_some());
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::body::Bytes;
+
+use crate::conditional;
+
+/// A file's content as served from the cache. Cloning it doesn't copy the content.
+#[derive(Clone)]
+pub(crate) struct CachedFile {
+    pub bytes: Bytes,
+    pub modified: Option<SystemTime>,
+    pub content_etag: Option<String>, // only computed for views using EtagMode::ContentHash
+}
+
+struct Entry {
+    file: CachedFile,
+    checked: Instant, // last time the file on disk was compared with the entry
+    tick: u64,        // key in Lru::order
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<PathBuf, Entry>,
+    order: BTreeMap<u64, PathBuf>, // least recently used first
+    next_tick: u64,
+    size: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, path: &Path) -> Option<CachedFile> {
+        let entry = self.entries.get_mut(path)?;
+        self.order.remove(&entry.tick);
+        entry.tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(entry.tick, path.to_path_buf());
+        Some(entry.file.clone())
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.tick);
+            self.size -= entry.file.bytes.len() as u64;
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, file: CachedFile, max_size: u64) {
+        self.remove(&path);
+        let len = file.bytes.len() as u64;
+        while self.size + len > max_size {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, path.clone());
+        self.entries.insert(
+            path,
+            Entry {
+                file,
+                checked: Instant::now(),
+                tick,
+            },
+        );
+        self.size += len;
+    }
+}
+
+/// A bounded in-memory cache of file contents for [`crate::views::StaticFiles`]. Files are
+/// evicted least recently used first once the total size would exceed the limit. An entry
+/// is reloaded when the file's mtime or size changed. Clones share the same cache.
+#[derive(Clone)]
+pub struct FileCache {
+    lru: Arc<Mutex<Lru>>,
+    max_size: u64,
+    max_file_size: u64,
+    check_interval: Duration,
+}
+
+impl FileCache {
+    /// Caches files up to `max_file_size` bytes, `max_size` bytes in total.
+    pub fn new(max_size: u64, max_file_size: u64) -> Self {
+        FileCache {
+            lru: Arc::new(Mutex::new(Lru::default())),
+            max_size,
+            max_file_size: max_file_size.min(max_size),
+            check_interval: Duration::ZERO,
+        }
+    }
+
+    /// How long an entry is served without checking the file on disk. By default every hit
+    /// costs a `stat`, a few seconds here trade freshness for fewer system calls.
+    pub fn check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// The cached content of `path`, loading it on a miss. None for files too large to cache.
+    /// With `hash` set the returned file has its content ETag filled in.
+    pub(crate) async fn get(&self, path: &Path, hash: bool) -> anyhow::Result<Option<CachedFile>> {
+        let hit = {
+            let mut lru = self.lru.lock().unwrap();
+            let fresh = match lru.entries.get(path) {
+                Some(entry) => entry.checked.elapsed() < self.check_interval,
+                None => false,
+            };
+            match fresh {
+                true => lru.touch(path),
+                false => None,
+            }
+        };
+        if let Some(file) = hit {
+            return Ok(Some(self.with_content_etag(path, file, hash)));
+        }
+
+        let metadata = tokio::fs::metadata(path).await?;
+        let modified = metadata.modified().ok();
+        let hit = {
+            let mut lru = self.lru.lock().unwrap();
+            let unchanged = match lru.entries.get_mut(path) {
+                Some(entry)
+                    if entry.file.modified == modified
+                        && entry.file.bytes.len() as u64 == metadata.len() =>
+                {
+                    entry.checked = Instant::now();
+                    true
+                }
+                _ => false,
+            };
+            if !unchanged {
+                lru.remove(path);
+            }
+            lru.touch(path)
+        };
+        if let Some(file) = hit {
+            return Ok(Some(self.with_content_etag(path, file, hash)));
+        }
+        if metadata.len() > self.max_file_size {
+            return Ok(None);
+        }
+
+        let bytes = Bytes::from(tokio::fs::read(path).await?);
+        let file = CachedFile {
+            content_etag: hash.then(|| conditional::content_etag(&bytes)),
+            bytes,
+            modified,
+        };
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), file.clone(), self.max_size);
+        Ok(Some(file))
+    }
+
+    // Fills in the content ETag of an entry loaded for a view that didn't need it. The hash is
+    // computed without holding the lock, other requests don't wait for it.
+    fn with_content_etag(&self, path: &Path, mut file: CachedFile, hash: bool) -> CachedFile {
+        if !hash || file.content_etag.is_some() {
+            return file;
+        }
+        let etag = conditional::content_etag(&file.bytes);
+        let mut lru = self.lru.lock().unwrap();
+        if let Some(entry) = lru.entries.get_mut(path) {
+            // Unless the file was reloaded in the meantime.
+            if entry.file.bytes.as_ptr() == file.bytes.as_ptr() {
+                entry.file.content_etag = Some(etag.clone());
+            }
+        }
+        file.content_etag = Some(etag);
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            Fixture {
+                dir: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    fn cached(cache: &FileCache) -> Vec<PathBuf> {
+        let lru = cache.lru.lock().unwrap();
+        lru.order.values().cloned().collect()
+    }
+
+    fn size(cache: &FileCache) -> u64 {
+        cache.lru.lock().unwrap().size
+    }
+
+    fn set_mtime(path: &Path, secs: u64) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_within_budget() {
+        let f = Fixture::new();
+        let a = f.write("a", "aaaa");
+        let b = f.write("b", "bbbb");
+        let c = f.write("c", "cccc");
+        let cache = FileCache::new(10, 10);
+
+        cache.get(&a, false).await.unwrap().unwrap();
+        cache.get(&b, false).await.unwrap().unwrap();
+        cache.get(&a, false).await.unwrap().unwrap();
+        assert_eq!(cached(&cache), [b.clone(), a.clone()]);
+        assert_eq!(size(&cache), 8);
+
+        // c doesn't fit next to both, b was used least recently.
+        cache.get(&c, false).await.unwrap().unwrap();
+        assert_eq!(cached(&cache), [a.clone(), c.clone()]);
+        assert_eq!(size(&cache), 8);
+
+        let big = f.write("big", "0123456789");
+        cache.get(&big, false).await.unwrap().unwrap();
+        assert_eq!(cached(&cache), [big]);
+        assert_eq!(size(&cache), 10);
+    }
+
+    #[tokio::test]
+    async fn skips_files_over_the_limit() {
+        let f = Fixture::new();
+        let small = f.write("small", "123");
+        let large = f.write("large", "12345");
+        let cache = FileCache::new(100, 4);
+        assert!(cache.get(&small, false).await.unwrap().is_some());
+        assert!(cache.get(&large, false).await.unwrap().is_none());
+        assert_eq!(cached(&cache), [small]);
+
+        // max_file_size is capped at max_size.
+        let cache = FileCache::new(2, 100);
+        assert!(cache
+            .get(&f.write("x", "123"), false)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reloads_when_mtime_or_size_changes() {
+        let f = Fixture::new();
+        let path = f.write("a", "one");
+        set_mtime(&path, 1_000);
+        let cache = FileCache::new(100, 100);
+        assert_eq!(cache.get(&path, false).await.unwrap().unwrap().bytes, "one");
+
+        // Same size, new mtime.
+        std::fs::write(&path, "two").unwrap();
+        set_mtime(&path, 2_000);
+        assert_eq!(cache.get(&path, false).await.unwrap().unwrap().bytes, "two");
+
+        // Same mtime, new size.
+        std::fs::write(&path, "three").unwrap();
+        set_mtime(&path, 2_000);
+        assert_eq!(
+            cache.get(&path, false).await.unwrap().unwrap().bytes,
+            "three"
+        );
+        assert_eq!(size(&cache), 5);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(cache.get(&path, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_unchecked_within_interval() {
+        let f = Fixture::new();
+        let path = f.write("a", "one");
+        set_mtime(&path, 1_000);
+        let cache = FileCache::new(100, 100).check_interval(Duration::from_secs(60));
+        cache.get(&path, false).await.unwrap().unwrap();
+
+        std::fs::write(&path, "two").unwrap();
+        set_mtime(&path, 2_000);
+        assert_eq!(cache.get(&path, false).await.unwrap().unwrap().bytes, "one");
+    }
+
+    #[tokio::test]
+    async fn hashes_only_when_asked() {
+        let f = Fixture::new();
+        let path = f.write("a", "hello");
+        let cache = FileCache::new(100, 100);
+        let file = cache.get(&path, false).await.unwrap().unwrap();
+        assert_eq!(file.content_etag, None);
+
+        let file = cache.get(&path, true).await.unwrap().unwrap();
+        assert_eq!(file.content_etag, Some(conditional::content_etag(b"hello")));
+        let stored = |cache: &FileCache| {
+            let lru = cache.lru.lock().unwrap();
+            lru.entries[&path].file.content_etag.clone()
+        };
+        assert_eq!(stored(&cache), file.content_etag);
+
+        // A hash of content that has since been reloaded isn't stored on the new entry.
+        let cache = FileCache::new(100, 100);
+        let old = cache.get(&path, false).await.unwrap().unwrap();
+        set_mtime(&path, 1);
+        cache.get(&path, false).await.unwrap().unwrap();
+        let old = cache.with_content_etag(&path, old, true);
+        assert!(old.content_etag.is_some());
+        assert_eq!(stored(&cache), None);
+
+        let other = f.write("b", "world");
+        let file = cache.get(&other, true).await.unwrap().unwrap();
+        assert_eq!(file.content_etag, Some(conditional::content_etag(b"world")));
+    }
+}
@@ -1,32 +1,50 @@
+use crate::connection::ConnectionInfo;
 use crate::context::Context;
+use crate::listener::Listener;
 use crate::middleware::Middleware;
 use crate::types::HttpResonse;
 use crate::view::View;
+use hyper::service::Service;
 use hyper::StatusCode;
-use hyper::{server::conn::http1, service::Service};
-
-use tokio::net::TcpListener;
 
 use route::match_view;
 
-use hyper_util::rt::TokioIo;
+use server::ServerConfig;
 use std::any;
 use std::future::Future;
-use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tls::TlsConfig;
 use types::{HttpRequest, State};
 
+mod autoindex;
+pub mod body;
+pub mod conditional;
+pub mod connection;
 pub mod context;
+pub mod embed;
+mod encoding;
+pub mod file_cache;
+pub mod listener;
 pub mod middleware;
 pub mod middlewares;
+pub mod proxy_protocol;
+mod range;
 pub mod response;
 pub mod route;
+pub mod server;
 pub mod session;
+pub mod sse;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod tls;
 pub mod types;
 pub mod utils;
 pub mod view;
 pub mod views;
+pub mod websocket;
 
 pub async fn apply_middlewares_pre(
     req: &mut HttpRequest,
@@ -57,13 +75,18 @@ pub async fn apply_middlewares_post(
     Ok(None)
 }
 
-async fn app_core(app: Arc<SimpleApi>, mut req: HttpRequest) -> anyhow::Result<HttpResonse> {
+async fn app_core(
+    app: Arc<SimpleApi>,
+    conn: Arc<ConnectionInfo>,
+    request_seq: u64,
+    mut req: HttpRequest,
+) -> anyhow::Result<HttpResonse> {
     let path = req.uri().path().to_string();
     let (view, mut ctx) = {
         let view_and_vpas = {
             let routes = app.routes();
 
-            match_view(&routes, &path)
+            match_view(routes, &path)
         };
         let (view, view_args) = match view_and_vpas {
             Some(v) => (Some(v.0), Some(v.1)),
@@ -74,15 +97,15 @@ async fn app_core(app: Arc<SimpleApi>, mut req: HttpRequest) -> anyhow::Result<H
 
         let state = app.state().clone();
 
-        let ctx = Context::new(sp, state, view_args);
+        let ctx = Context::new(sp, state, view_args, conn, request_seq);
         (view, ctx)
     };
 
     let middlewares = app.middlewares();
-    match apply_middlewares_pre(&mut req, &mut ctx, &middlewares).await {
+    match apply_middlewares_pre(&mut req, &mut ctx, middlewares).await {
         Ok(None) => (),
         Ok(Some(v)) => return Ok(v),
-        Err(e) => return Ok(response::internal_server_error(e).unwrap()),
+        Err(e) => return Ok(response::error_response(e)),
     }
 
     let Some(view) = view else {
@@ -97,14 +120,14 @@ async fn app_core(app: Arc<SimpleApi>, mut req: HttpRequest) -> anyhow::Result<H
     match view.call(&mut req, &mut ctx).await {
         Ok(r) => {
             let mut res = r;
-            match apply_middlewares_post(&mut req, &mut res, &mut ctx, &middlewares).await {
+            match apply_middlewares_post(&mut req, &mut res, &mut ctx, middlewares).await {
                 Ok(None) => (),
                 Ok(Some(v)) => return Ok(v),
-                Err(e) => return Ok(response::internal_server_error(e).unwrap()),
+                Err(e) => return Ok(response::error_response(e)),
             }
             Ok(res)
         }
-        Err(e) => Ok(response::internal_server_error(e).unwrap()),
+        Err(e) => Ok(response::error_response(e)),
     }
 }
 
@@ -113,15 +136,36 @@ pub struct SimpleApi {
     middlewares: Vec<Arc<dyn Middleware>>,
     session_provider: Option<Arc<dyn session::SessionProvider>>,
     state: State,
+    server_config: ServerConfig,
 }
 
 pub struct SimpleApiService {
     inner: Arc<SimpleApi>,
+    conn: Arc<ConnectionInfo>,
+    requests: AtomicU64, // number of requests seen on this connection so far
+    drain: Option<server::Drain>, // None when not run by our server
 }
 
 impl SimpleApiService {
-    fn new(inner: Arc<SimpleApi>) -> Self {
-        SimpleApiService { inner }
+    fn new(inner: Arc<SimpleApi>, conn: ConnectionInfo) -> Self {
+        SimpleApiService {
+            inner,
+            conn: Arc::new(conn),
+            requests: AtomicU64::new(0),
+            drain: None,
+        }
+    }
+
+    // Hands the server's shutdown to requests through their extensions.
+    pub(crate) fn drain(mut self, drain: server::Drain) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+}
+
+impl Default for SimpleApi {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -133,50 +177,117 @@ impl SimpleApi {
             middlewares,
             session_provider: None,
             state: Arc::new(()),
+            server_config: ServerConfig::default(),
         }
     }
 
-    pub fn routes<'s>(&'s self) -> &'s Vec<Arc<dyn View>> {
+    pub fn routes(&self) -> &Vec<Arc<dyn View>> {
         &self.routes
     }
 
-    pub fn middlewares<'s>(&'s self) -> &'s Vec<Arc<dyn Middleware>> {
+    pub fn middlewares(&self) -> &Vec<Arc<dyn Middleware>> {
         &self.middlewares
     }
 
-    pub fn session_provider<'s>(&'s self) -> &'s Option<Arc<dyn session::SessionProvider>> {
+    pub fn session_provider(&self) -> &Option<Arc<dyn session::SessionProvider>> {
         &self.session_provider
     }
 
-    pub fn state<'s>(&'s self) -> &'s State {
+    pub fn state(&self) -> &State {
         &self.state
     }
 
+    pub fn server_config(&self) -> &ServerConfig {
+        &self.server_config
+    }
+
     pub fn add_route<T: any::Any + View>(&mut self, view: T) {
         self.routes.push(Arc::new(view));
     }
 
     pub fn service(self: &Arc<Self>) -> SimpleApiService {
-        SimpleApiService::new(self.clone())
+        self.connection_service(ConnectionInfo::default())
+    }
+
+    /// A service for a single connection, every request on it sees `conn` in its Context.
+    pub fn connection_service(self: &Arc<Self>, conn: ConnectionInfo) -> SimpleApiService {
+        SimpleApiService::new(self.clone(), conn)
     }
 
+    /// Serves until SIGINT or SIGTERM is received, then shuts down gracefully.
     pub async fn run(self, addr: &str) -> anyhow::Result<()> {
-        let _self = Arc::new(self);
-        let addr = addr.parse::<SocketAddr>().unwrap();
-        let listener = TcpListener::bind(addr).await?;
-
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let io = TokioIo::new(stream);
-            let service = _self.service();
-            tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                    println!("Error serving connection: {:?}", err);
-                }
-            });
-        }
+        self.run_with_shutdown(addr, server::shutdown_signal())
+            .await
+    }
+
+    /// Serves until `signal` resolves. In-flight connections get
+    /// [`ServerConfig::shutdown_timeout`] to finish.
+    pub async fn run_with_shutdown<F>(self, addr: &str, signal: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = Listener::bind_tcp(addr, &self.server_config)?;
+        self.run_listeners_with_shutdown(vec![listener], signal)
+            .await
+    }
+
+    /// Serves HTTPS with the PEM certificate chain and key read from the given paths.
+    /// Shuts down like [`SimpleApi::run`].
+    pub async fn run_tls(self, addr: &str, cert_chain: &str, key: &str) -> anyhow::Result<()> {
+        self.run_tls_with_config(addr, TlsConfig::new(cert_chain, key))
+            .await
+    }
+
+    pub async fn run_tls_with_config(self, addr: &str, tls: TlsConfig) -> anyhow::Result<()> {
+        self.run_tls_with_shutdown(addr, tls, server::shutdown_signal())
+            .await
+    }
+
+    /// Serves HTTPS until `signal` resolves, see [`SimpleApi::run_with_shutdown`].
+    pub async fn run_tls_with_shutdown<F>(
+        self,
+        addr: &str,
+        tls: TlsConfig,
+        signal: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = Listener::bind_tcp(addr, &self.server_config)?.tls(tls);
+        self.run_listeners_with_shutdown(vec![listener], signal)
+            .await
+    }
+
+    /// Serves on a unix domain socket, replacing a stale socket file left at `path`.
+    #[cfg(unix)]
+    pub async fn run_unix(self, path: &str) -> anyhow::Result<()> {
+        let listener = Listener::bind_unix(path, &listener::UnixSocketOptions::default())?;
+        self.run_listeners(vec![listener]).await
+    }
+
+    /// Serves on the sockets passed by systemd socket activation.
+    #[cfg(unix)]
+    pub async fn run_from_listen_fds(self) -> anyhow::Result<()> {
+        let listeners = Listener::from_listen_fds(&self.server_config)?;
+        self.run_listeners(listeners).await
+    }
+
+    /// Serves on several listeners at once, e.g. a unix socket for the proxy and a TCP port
+    /// for health checks. Shuts down like [`SimpleApi::run`].
+    pub async fn run_listeners(self, listeners: Vec<Listener>) -> anyhow::Result<()> {
+        self.run_listeners_with_shutdown(listeners, server::shutdown_signal())
+            .await
+    }
 
-        Ok(())
+    pub async fn run_listeners_with_shutdown<F>(
+        self,
+        listeners: Vec<Listener>,
+        signal: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        server::serve(Arc::new(self), listeners, signal).await
     }
 
     pub fn add_middleware(&mut self, m: Arc<dyn Middleware>) {
@@ -190,6 +301,15 @@ impl SimpleApi {
     pub fn set_state(&mut self, state: State) {
         self.state = state;
     }
+
+    pub fn set_server_config(&mut self, config: ServerConfig) {
+        self.server_config = config;
+    }
+
+    /// Same as setting [`ServerConfig::shutdown_timeout`] on the current config.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.server_config = std::mem::take(&mut self.server_config).shutdown_timeout(timeout);
+    }
 }
 
 impl Service<HttpRequest> for SimpleApiService {
@@ -197,9 +317,13 @@ impl Service<HttpRequest> for SimpleApiService {
     type Error = anyhow::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: HttpRequest) -> Self::Future {
-        let res = app_core(self.inner.clone(), req);
+    fn call(&self, mut req: HttpRequest) -> Self::Future {
+        if let Some(ref drain) = self.drain {
+            req.extensions_mut().insert(drain.clone());
+        }
+        let request_seq = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let res = app_core(self.inner.clone(), self.conn.clone(), request_seq, req);
 
-        Box::pin(async { res.await })
+        Box::pin(res)
     }
 }
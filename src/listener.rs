@@ -0,0 +1,367 @@
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::connection::ConnectionInfo;
+use crate::server::{BoxedIo, ServerConfig};
+use crate::tls::TlsConfig;
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+enum Kind {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A socket the server accepts connections on. Several of them can be served at once with
+/// [`crate::SimpleApi::run_listeners`].
+pub struct Listener {
+    kind: Kind,
+    tls: Option<TlsConfig>,
+    proxy_protocol: Option<Duration>,
+    tcp_nodelay: bool,
+    #[cfg(unix)]
+    cleanup: Option<PathBuf>, // socket file to remove once the listener is dropped
+}
+
+impl Listener {
+    fn new(kind: Kind) -> Self {
+        Listener {
+            kind,
+            tls: None,
+            proxy_protocol: None,
+            tcp_nodelay: false,
+            #[cfg(unix)]
+            cleanup: None,
+        }
+    }
+
+    /// Binds a TCP socket using the backlog, SO_REUSEPORT and TCP_NODELAY settings of `config`.
+    pub fn bind_tcp(addr: &str, config: &ServerConfig) -> anyhow::Result<Self> {
+        let addr = addr.parse::<SocketAddr>()?;
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        #[cfg(unix)]
+        {
+            socket.set_reuseaddr(true)?;
+            socket.set_reuseport(config.get_reuse_port())?;
+        }
+        socket.bind(addr)?;
+        let mut listener = Listener::new(Kind::Tcp(socket.listen(config.get_backlog())?));
+        listener.tcp_nodelay = config.get_tcp_nodelay();
+        Ok(listener)
+    }
+
+    /// Binds a unix domain socket at `path`, see [`UnixSocketOptions`] for what happens to an
+    /// existing file there. The socket file is removed again when the listener is dropped.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, options: &UnixSocketOptions) -> anyhow::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = path.as_ref();
+        if options.remove_stale {
+            remove_stale_socket(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let mut listener = Listener::new(Kind::Unix(listener));
+        listener.cleanup = Some(path.to_path_buf());
+        if let Some(mode) = options.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if options.uid.is_some() || options.gid.is_some() {
+            std::os::unix::fs::chown(path, options.uid, options.gid)?;
+        }
+        Ok(listener)
+    }
+
+    /// Takes over the sockets passed by systemd socket activation (`LISTEN_PID`/`LISTEN_FDS`).
+    /// Both TCP and unix stream sockets are supported, TCP ones get the TCP_NODELAY setting of
+    /// `config`. Returns an empty Vec when the process wasn't socket activated or the sockets
+    /// were already taken by an earlier call. The environment is left untouched, child
+    /// processes ignore the variables since `LISTEN_PID` doesn't match their pid.
+    #[cfg(unix)]
+    pub fn from_listen_fds(config: &ServerConfig) -> anyhow::Result<Vec<Self>> {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // sd_listen_fds(3): passed descriptors start at 3.
+        const LISTEN_FDS_START: i32 = 3;
+        // The descriptors can only be owned once, a second call would close them twice.
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+
+        let pid = match std::env::var("LISTEN_PID") {
+            Ok(v) => v.parse::<u32>()?,
+            Err(_) => return Ok(vec![]),
+        };
+        if pid != std::process::id() {
+            return Ok(vec![]);
+        }
+        let count = std::env::var("LISTEN_FDS")?.parse::<i32>()?;
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(vec![]);
+        }
+
+        let mut listeners = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // A unix socket reports a unix local address, anything else fails the lookup.
+            let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            let kind = if unix.local_addr().is_ok() {
+                unix.set_nonblocking(true)?;
+                Kind::Unix(UnixListener::from_std(unix)?)
+            } else {
+                let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+                tcp.local_addr()
+                    .map_err(|e| anyhow!("LISTEN_FDS fd {} is not a stream socket: {}", fd, e))?;
+                tcp.set_nonblocking(true)?;
+                Kind::Tcp(TcpListener::from_std(tcp)?)
+            };
+            let mut listener = Listener::new(kind);
+            listener.tcp_nodelay = config.get_tcp_nodelay();
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Terminates TLS on connections accepted by this listener.
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub fn tls_config(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Expects every connection to start with a PROXY protocol v1 or v2 header, as sent by
+    /// HAProxy or an AWS NLB. Connections without a valid header within `header_timeout` are
+    /// closed. Only enable this behind a proxy, otherwise clients can claim any address.
+    pub fn proxy_protocol(mut self, header_timeout: Duration) -> Self {
+        self.proxy_protocol = Some(header_timeout);
+        self
+    }
+
+    pub fn proxy_protocol_timeout(&self) -> Option<Duration> {
+        self.proxy_protocol
+    }
+
+    // The returned ConnectionInfo only has the socket addresses filled in.
+    pub(crate) fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(BoxedIo, ConnectionInfo)>> {
+        match self.kind {
+            Kind::Tcp(ref l) => l.poll_accept(cx).map_ok(|(stream, peer_addr)| {
+                if self.tcp_nodelay {
+                    let _ = stream.set_nodelay(true);
+                }
+                let info = ConnectionInfo {
+                    peer_addr: Some(peer_addr),
+                    local_addr: stream.local_addr().ok(),
+                    ..Default::default()
+                };
+                (Box::new(stream) as BoxedIo, info)
+            }),
+            #[cfg(unix)]
+            Kind::Unix(ref l) => l
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| (Box::new(stream) as BoxedIo, ConnectionInfo::default())),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::new(Kind::Tcp(listener))
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::new(Kind::Unix(listener))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.cleanup {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixSocketOptions {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    remove_stale: bool,
+}
+
+#[cfg(unix)]
+impl Default for UnixSocketOptions {
+    fn default() -> Self {
+        UnixSocketOptions {
+            mode: None,
+            uid: None,
+            gid: None,
+            remove_stale: true,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UnixSocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// File mode of the socket, e.g. `0o660` so the proxy's group can connect.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Owner of the socket file. Changing the user usually requires root.
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Removes a socket file left behind by a previous process that is no longer listening,
+    /// enabled by default. Regular files and sockets that still accept connections are never
+    /// removed.
+    pub fn remove_stale(mut self, enabled: bool) -> Self {
+        self.remove_stale = enabled;
+        self
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{} exists and is not a socket", path.display()));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!("{} is in use by another process", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::request_on;
+    use crate::SimpleApi;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn socket_path(dir: &tempfile::TempDir) -> PathBuf {
+        dir.path().join("app.sock")
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path, &UnixSocketOptions::new()).unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let run = tokio::spawn(SimpleApi::new().run_listeners_with_shutdown(
+            vec![listener],
+            async {
+                let _ = rx.await;
+            },
+        ));
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert_eq!(
+            request_on(&mut stream, "GET /missing HTTP/1.1", b"")
+                .await
+                .status,
+            404
+        );
+        tx.send(()).unwrap();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_stale_socket_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let options = UnixSocketOptions::new().remove_stale(false);
+        assert!(Listener::bind_unix(&path, &options).is_err());
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_socket_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let _live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let err = Listener::bind_unix(&path, &UnixSocketOptions::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        std::fs::write(&path, "data").unwrap();
+        let err = Listener::bind_unix(&path, &UnixSocketOptions::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+
+    #[tokio::test]
+    async fn applies_mode_and_removes_socket_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(&dir);
+        let options = UnixSocketOptions::new().mode(0o660);
+        let listener = Listener::bind_unix(&path, &options).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn no_listen_fds_without_socket_activation() {
+        // The test process is never socket activated, or LISTEN_PID names the parent.
+        if std::env::var("LISTEN_PID").ok() == Some(std::process::id().to_string()) {
+            return;
+        }
+        let listeners = Listener::from_listen_fds(&ServerConfig::default()).unwrap();
+        assert!(listeners.is_empty());
+    }
+}
@@ -1,12 +1,30 @@
 use crate::middleware::Middleware;
-use crate::{context::Context, types::HttpRequest};
+use crate::{
+    body::BodyStream,
+    context::{self, Context, ForwardedInfo},
+    response::HttpError,
+    types::HttpRequest,
+};
 
+use crate::encoding::{self, Encoding};
 pub use crate::types::HttpResonse;
-use crate::utils;
+use crate::{response, session, utils};
 use anyhow::Ok;
 use async_trait::async_trait;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use hmac::Mac;
+use ipnet::IpNet;
+use sha2::Sha256;
+use std::net::IpAddr;
 
-use hyper::header;
+use hyper::body::Body;
+use hyper::{header, HeaderMap, Method, Request, Response, StatusCode};
+use regex::Regex;
+use serde_json::json;
+use std::time::Duration;
 pub struct SessionMiddleware;
 
 #[async_trait]
@@ -44,3 +62,2054 @@ impl Middleware for SessionMiddleware {
         Ok(None)
     }
 }
+
+/// Resolves the client IP, scheme, host and path prefix from `Forwarded` (RFC 7239) or
+/// `X-Forwarded-For/Proto/Host/Prefix` into `ctx.forwarded`. The headers are only believed when
+/// the immediate peer is a trusted proxy, otherwise the connection's own values are used.
+pub struct ForwardedMiddleware {
+    trusted: Vec<IpNet>,
+    trust_unix_socket: bool,
+}
+
+impl ForwardedMiddleware {
+    pub fn new(trusted: Vec<IpNet>) -> Self {
+        ForwardedMiddleware {
+            trusted,
+            trust_unix_socket: false,
+        }
+    }
+
+    /// Accepts CIDRs like "10.0.0.0/8" as well as plain addresses.
+    pub fn from_cidrs(cidrs: &[&str]) -> anyhow::Result<Self> {
+        let mut trusted = Vec::new();
+        for cidr in cidrs {
+            let net = match cidr.parse::<IpNet>() {
+                std::result::Result::Ok(v) => v,
+                Err(_) => IpNet::from(cidr.parse::<IpAddr>()?),
+            };
+            trusted.push(net);
+        }
+        Ok(Self::new(trusted))
+    }
+
+    /// Unix socket peers have no address, trust them when only the proxy can reach the socket.
+    pub fn trust_unix_socket(mut self, enabled: bool) -> Self {
+        self.trust_unix_socket = enabled;
+        self
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    // The rightmost hop that isn't a trusted proxy is the client, proxies can't vouch for
+    // anything to the left of it.
+    fn pick_client<'h>(&self, hops: &'h [Hop]) -> Option<&'h Hop> {
+        let mut picked = None;
+        for hop in hops.iter().rev() {
+            picked = Some(hop);
+            match hop.ip {
+                Some(ref ip) if self.is_trusted(ip) => continue,
+                _ => break,
+            }
+        }
+        picked
+    }
+}
+
+#[derive(Default)]
+struct Hop {
+    ip: Option<IpAddr>, // None for "unknown" and obfuscated identifiers
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn header_values<'r>(headers: &'r HeaderMap, name: &str) -> Vec<&'r str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+// Accepts "192.0.2.1", "192.0.2.1:80", "[2001:db8::1]" and "[2001:db8::1]:80".
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    if let std::result::Result::Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+fn parse_scheme(proto: &str) -> Option<String> {
+    match proto.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+        v @ ("http" | "https") => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_forwarded(headers: &HeaderMap) -> Vec<Hop> {
+    header_values(headers, "forwarded")
+        .into_iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim();
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.ip = parse_node(value),
+                    "proto" => hop.proto = parse_scheme(value),
+                    "host" => hop.host = Some(value.trim_matches('"').to_string()),
+                    _ => (),
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+// Every proxy appends one entry to each X-Forwarded-* header, so the entries describing the
+// same hop line up when counted from the right. Without X-Forwarded-For the trusted peer is
+// the only hop.
+fn parse_x_forwarded(headers: &HeaderMap, peer: Option<IpAddr>) -> Vec<Hop> {
+    let ips = header_values(headers, "x-forwarded-for");
+    let protos = header_values(headers, "x-forwarded-proto");
+    let hosts = header_values(headers, "x-forwarded-host");
+    let ips = match ips.is_empty() {
+        true => vec![None],
+        false => ips.into_iter().map(Some).collect(),
+    };
+    let count = ips.len();
+    ips.into_iter()
+        .enumerate()
+        .map(|(i, ip)| {
+            let from_right = count - 1 - i;
+            Hop {
+                ip: match ip {
+                    Some(v) => parse_node(v),
+                    None => peer,
+                },
+                proto: protos
+                    .iter()
+                    .rev()
+                    .nth(from_right)
+                    .and_then(|v| parse_scheme(v)),
+                host: hosts.iter().rev().nth(from_right).map(|v| v.to_string()),
+            }
+        })
+        .collect()
+}
+
+impl ForwardedMiddleware {
+    fn resolve(
+        &self,
+        headers: &HeaderMap,
+        authority: Option<String>,
+        peer: Option<IpAddr>,
+    ) -> ForwardedInfo {
+        // A Host that isn't visible ASCII is ignored like a missing one.
+        let host = match headers.get(header::HOST) {
+            Some(v) => v.to_str().ok().map(|v| v.to_string()),
+            None => authority,
+        };
+        let mut info = ForwardedInfo {
+            client_ip: peer,
+            scheme: None,
+            host,
+            prefix: None,
+        };
+        let peer_trusted = match peer {
+            Some(ref ip) => self.is_trusted(ip),
+            None => self.trust_unix_socket,
+        };
+        if !peer_trusted {
+            return info;
+        }
+
+        let hops = match headers.contains_key("forwarded") {
+            true => parse_forwarded(headers),
+            false => parse_x_forwarded(headers, peer),
+        };
+        if let Some(hop) = self.pick_client(&hops) {
+            info.client_ip = hop.ip;
+            info.scheme = hop.proto.clone();
+            if hop.host.is_some() {
+                info.host = hop.host.clone();
+            }
+        }
+        if let Some(prefix) = header_values(headers, "x-forwarded-prefix").first() {
+            let prefix = prefix.trim_end_matches('/');
+            if prefix.starts_with('/') {
+                info.prefix = Some(prefix.to_string());
+            }
+        }
+        info
+    }
+}
+
+#[async_trait]
+impl Middleware for ForwardedMiddleware {
+    async fn pre_process(
+        &self,
+        req: &mut HttpRequest,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        let peer = ctx.conn.client_addr().map(|v| v.ip());
+        let authority = req.uri().authority().map(|v| v.to_string());
+        ctx.forwarded = Some(self.resolve(req.headers(), authority, peer));
+        Ok(None)
+    }
+
+    async fn post_process(
+        &self,
+        _req: &mut HttpRequest,
+        _res: &mut HttpResonse,
+        _ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        Ok(None)
+    }
+}
+
+/// Compresses responses with brotli, zstd or gzip, whichever the client prefers. Only
+/// responses of a compressible type and at least `min_size` bytes are compressed, responses
+/// that are already encoded or marked `no-transform` are left alone. Streamed bodies are
+/// compressed as they are sent.
+pub struct CompressionMiddleware {
+    min_size: u64,
+    content_types: Vec<String>,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> Self {
+        CompressionMiddleware {
+            min_size: 1024,
+            content_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+                "+json",
+                "+xml",
+            ]
+            .iter()
+            .map(|v| v.to_string())
+            .collect(),
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+        }
+    }
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Smaller responses aren't worth compressing, 1024 bytes by default. Streamed responses
+    /// of unknown length are always compressed.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Media types to compress. `text/` matches a whole top-level type, `+json` a suffix,
+    /// anything else the exact type. `text/event-stream` is never compressed, the encoder
+    /// would hold events back.
+    pub fn content_types(mut self, types: &[&str]) -> Self {
+        self.content_types = types.iter().map(|v| v.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn brotli(self, enabled: bool) -> Self {
+        self.encoding(Encoding::Brotli, enabled)
+    }
+
+    pub fn zstd(self, enabled: bool) -> Self {
+        self.encoding(Encoding::Zstd, enabled)
+    }
+
+    pub fn gzip(self, enabled: bool) -> Self {
+        self.encoding(Encoding::Gzip, enabled)
+    }
+
+    fn encoding(mut self, encoding: Encoding, enabled: bool) -> Self {
+        self.encodings.retain(|v| *v != encoding);
+        if enabled {
+            self.encodings.push(encoding);
+            // Keep the server preference brotli, zstd, gzip for ties in Accept-Encoding.
+            self.encodings.sort_by_key(|v| match v {
+                Encoding::Brotli => 0,
+                Encoding::Zstd => 1,
+                Encoding::Gzip => 2,
+            });
+        }
+        self
+    }
+
+    fn is_compressible_type(&self, res: &HttpResonse) -> bool {
+        let content_type = match res.headers().get(header::CONTENT_TYPE) {
+            Some(v) => v.to_str().unwrap_or(""),
+            None => return false,
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        if mime == "text/event-stream" {
+            return false;
+        }
+        self.content_types.iter().any(|v| {
+            if v.ends_with('/') {
+                mime.starts_with(v.as_str())
+            } else if v.starts_with('+') {
+                mime.ends_with(v.as_str())
+            } else {
+                mime == *v
+            }
+        })
+    }
+
+    fn is_compressible(&self, res: &HttpResonse) -> bool {
+        let status = res.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+        let headers = res.headers();
+        if headers.contains_key(header::CONTENT_ENCODING)
+            || headers.contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+        if no_transform || !self.is_compressible_type(res) {
+            return false;
+        }
+        let len = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(res.body().size_hint().exact());
+        match len {
+            Some(len) => len >= self.min_size,
+            None => true,
+        }
+    }
+
+    fn compress<B>(&self, req: &Request<B>, res: &mut HttpResonse) -> anyhow::Result<()> {
+        if req.method() == Method::HEAD || !self.is_compressible(res) {
+            return Ok(());
+        }
+        add_vary(res, "accept-encoding")?;
+        let encoding = match encoding::preferred(req, &self.encodings) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let body = std::mem::replace(res.body_mut(), response::empty_body());
+        *res.body_mut() = encoding::encode(body, encoding);
+        let headers = res.headers_mut();
+        headers.insert(header::CONTENT_ENCODING, encoding.name().parse()?);
+        headers.remove(header::CONTENT_LENGTH);
+        // Ranges would refer to the compressed bytes, which differ between responses.
+        headers.remove(header::ACCEPT_RANGES);
+        // The compressed bytes aren't the identity representation the ETag was made for.
+        if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+            if !etag.starts_with("W/") {
+                let weak = format!("W/{}", etag);
+                headers.insert(header::ETAG, weak.parse()?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_vary(res: &mut HttpResonse, name: &str) -> anyhow::Result<()> {
+    let present = res
+        .headers()
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(name));
+    if !present {
+        res.headers_mut().append(header::VARY, name.parse()?);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Middleware for CompressionMiddleware {
+    async fn pre_process(
+        &self,
+        _req: &mut HttpRequest,
+        _ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        Ok(None)
+    }
+
+    async fn post_process(
+        &self,
+        req: &mut HttpRequest,
+        res: &mut HttpResonse,
+        _ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        self.compress(req, res)?;
+        Ok(None)
+    }
+}
+
+enum AllowedOrigin {
+    Any,
+    Exact(String),
+    Wildcard(String, String), // what comes before and after the `*`
+    Pattern(Regex),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(v) => v.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Wildcard(prefix, suffix) => {
+                let origin = origin.to_ascii_lowercase();
+                match origin
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|v| v.strip_suffix(suffix.as_str()))
+                {
+                    // The `*` stands for host labels only, not a port or a path.
+                    Some(middle) => {
+                        !middle.is_empty()
+                            && middle
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                    }
+                    None => false,
+                }
+            }
+            AllowedOrigin::Pattern(re) => re.is_match(origin),
+            AllowedOrigin::Predicate(f) => f(origin),
+        }
+    }
+}
+
+/// Cross-origin resource sharing. Preflight `OPTIONS` requests are answered before routing,
+/// so views don't need to list `OPTIONS` in their methods. No origin is allowed until one is
+/// added with one of the `allow_origin` methods.
+pub struct CorsMiddleware {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Option<Vec<String>>, // None allows whatever the preflight asks for
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsMiddleware {
+    fn default() -> Self {
+        CorsMiddleware {
+            origins: Vec::new(),
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An exact origin like "https://app.example.com", "*" for any origin, or a single `*`
+    /// standing for subdomains like "https://*.example.com".
+    ///
+    /// # Panics
+    ///
+    /// On "*" when credentials are allowed, see [`CorsMiddleware::allow_credentials`].
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+        let allowed = match origin.split_once('*') {
+            Some(("", "")) => {
+                assert!(!self.credentials, "{}", ANY_ORIGIN_WITH_CREDENTIALS);
+                AllowedOrigin::Any
+            }
+            Some((prefix, suffix)) => {
+                AllowedOrigin::Wildcard(prefix.to_string(), suffix.to_string())
+            }
+            None => AllowedOrigin::Exact(origin),
+        };
+        self.origins.push(allowed);
+        self
+    }
+
+    /// Origins matching `pattern`, anchor it with `^` and `$`.
+    pub fn allow_origin_regex(mut self, pattern: Regex) -> Self {
+        self.origins.push(AllowedOrigin::Pattern(pattern));
+        self
+    }
+
+    pub fn allow_origin_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origins.push(AllowedOrigin::Predicate(Box::new(f)));
+        self
+    }
+
+    /// Methods a preflight may ask for, GET, HEAD, POST, PUT, PATCH and DELETE by default.
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Request headers a preflight may ask for. By default any requested header is allowed.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = Some(headers.iter().map(|v| v.to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// Response headers scripts may read besides the CORS-safelisted ones.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|v| v.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Lets requests carry cookies. Browsers refuse `Access-Control-Allow-Origin: *` on such
+    /// requests, and echoing every origin instead would let any site make authenticated
+    /// requests and read the responses. List the trusted origins instead.
+    ///
+    /// # Panics
+    ///
+    /// When "*" was passed to [`CorsMiddleware::allow_origin`]. A regex or predicate that
+    /// accepts every origin can't be detected and is just as unsafe.
+    pub fn allow_credentials(mut self, enabled: bool) -> Self {
+        assert!(
+            !enabled || !self.allows_any_origin(),
+            "{}",
+            ANY_ORIGIN_WITH_CREDENTIALS
+        );
+        self.credentials = enabled;
+        self
+    }
+
+    /// How long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|v| matches!(v, AllowedOrigin::Any))
+    }
+
+    // Responses are the same for every origin only when they all get `*`.
+    fn varies_by_origin(&self) -> bool {
+        !self.allows_any_origin()
+    }
+
+    // The Access-Control-Allow-Origin value for the request, None when its origin isn't allowed.
+    fn allowed_origin<B>(&self, req: &Request<B>) -> Option<String> {
+        let origin = req.headers().get(header::ORIGIN)?.to_str().ok()?;
+        if !self.origins.iter().any(|v| v.matches(origin)) {
+            return None;
+        }
+        match self.varies_by_origin() {
+            true => Some(origin.to_string()),
+            false => Some("*".to_string()),
+        }
+    }
+
+    fn preflight<B>(&self, req: &Request<B>) -> anyhow::Result<HttpResonse> {
+        let mut res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(response::empty_body())?;
+        if self.varies_by_origin() {
+            add_vary(&mut res, "origin")?;
+        }
+        add_vary(&mut res, "access-control-request-method")?;
+        if self.headers.is_none() {
+            add_vary(&mut res, "access-control-request-headers")?;
+        }
+        // Without the allow headers the browser fails the actual request.
+        let Some(origin) = self.allowed_origin(req) else {
+            return Ok(res);
+        };
+        let headers = res.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.parse()?);
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".parse()?);
+        }
+        let methods = self
+            .methods
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods.parse()?);
+        let allowed_headers = match self.headers {
+            Some(ref v) => Some(v.join(", ").parse()?),
+            None => req
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned(),
+        };
+        if let Some(v) = allowed_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(
+                header::ACCESS_CONTROL_MAX_AGE,
+                max_age.as_secs().to_string().parse()?,
+            );
+        }
+        Ok(res)
+    }
+
+    // Answers preflights, anything else goes on to routing.
+    fn short_circuit<B>(&self, req: &Request<B>) -> anyhow::Result<Option<HttpResonse>> {
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ORIGIN)
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if !is_preflight {
+            return Ok(None);
+        }
+        Ok(Some(self.preflight(req)?))
+    }
+
+    fn add_headers<B>(&self, req: &Request<B>, res: &mut HttpResonse) -> anyhow::Result<()> {
+        if self.varies_by_origin() {
+            add_vary(res, "origin")?;
+        }
+        let Some(origin) = self.allowed_origin(req) else {
+            return Ok(());
+        };
+        let headers = res.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.parse()?);
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".parse()?);
+        }
+        if !self.expose_headers.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                self.expose_headers.join(", ").parse()?,
+            );
+        }
+        Ok(())
+    }
+}
+
+const ANY_ORIGIN_WITH_CREDENTIALS: &str =
+    "CorsMiddleware: allow_origin(\"*\") can't be combined with allow_credentials(true)";
+
+#[async_trait]
+impl Middleware for CorsMiddleware {
+    async fn pre_process(
+        &self,
+        req: &mut HttpRequest,
+        _ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        self.short_circuit(req)
+    }
+
+    async fn post_process(
+        &self,
+        req: &mut HttpRequest,
+        res: &mut HttpResonse,
+        _ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        self.add_headers(req, res)?;
+        Ok(None)
+    }
+}
+
+/// Where `CsrfMiddleware` keeps the token the client has to send back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfMode {
+    /// In the session under `_csrf_token`, needs a session provider.
+    Session,
+    /// In a cookie scripts can read, for APIs without sessions. A request is accepted when
+    /// the submitted token equals the cookie. Other sites can't read the cookie, but a
+    /// sibling subdomain or a man in the middle on plain HTTP can set it, so the token is
+    /// signed with the middleware's key and a cookie with a bad signature counts as missing.
+    /// That stops made-up tokens, not a token the attacker got from this server for
+    /// themselves; use [`CsrfMode::Session`] when that matters.
+    DoubleSubmitCookie,
+}
+
+const CSRF_SESSION_KEY: &str = "_csrf_token";
+
+/// Rejects unsafe requests (anything but GET, HEAD, OPTIONS and TRACE) that don't carry the
+/// CSRF token with a 403. The token is taken from the `X-CSRF-Token` header, or else from the
+/// `csrf_token` field of a url-encoded form. Views render it from [`Context::csrf_token`].
+pub struct CsrfMiddleware {
+    mode: CsrfMode,
+    header_name: String,
+    field_name: String,
+    cookie_name: String,
+    exempt: Vec<Regex>,
+    form_limit: u64,
+    key: hmac::Hmac<Sha256>,
+}
+
+impl CsrfMiddleware {
+    pub fn new(mode: CsrfMode) -> Self {
+        let secret = format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        CsrfMiddleware {
+            mode,
+            header_name: "x-csrf-token".to_string(),
+            field_name: "csrf_token".to_string(),
+            cookie_name: "csrf_token".to_string(),
+            exempt: Vec::new(),
+            form_limit: 1024 * 1024,
+            key: hmac::Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC takes keys of any size"),
+        }
+    }
+
+    /// The key tokens are signed with. A random one is made by default, so tokens don't
+    /// survive a restart and aren't accepted by other instances behind the same load balancer.
+    pub fn key(mut self, key: hmac::Hmac<Sha256>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn header_name(mut self, name: &str) -> Self {
+        self.header_name = name.to_ascii_lowercase();
+        self
+    }
+
+    pub fn field_name(mut self, name: &str) -> Self {
+        self.field_name = name.to_string();
+        self
+    }
+
+    /// Only used in [`CsrfMode::DoubleSubmitCookie`].
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Requests whose path matches aren't checked, e.g. webhooks authenticated otherwise.
+    pub fn exempt(mut self, path: Regex) -> Self {
+        self.exempt.push(path);
+        self
+    }
+
+    /// The most a form body may have when the token has to be looked up in it, 1 MiB by
+    /// default.
+    pub fn form_limit(mut self, bytes: u64) -> Self {
+        self.form_limit = bytes;
+        self
+    }
+
+    // A random value and its signature, `<value>.<signature>`.
+    fn new_token(&self) -> String {
+        let value = uuid::Uuid::new_v4().simple().to_string();
+        let signature = session::get_signature(&self.key, &value);
+        format!("{}.{}", value, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn is_signed(&self, token: &str) -> bool {
+        let (value, signature) = match token.split_once('.') {
+            Some(v) => v,
+            None => return false,
+        };
+        let signature = match URL_SAFE_NO_PAD.decode(signature) {
+            Result::Ok(v) => v,
+            Err(_) => return false,
+        };
+        let mut key = self.key.clone();
+        key.update(value.as_bytes());
+        key.verify_slice(&signature).is_ok()
+    }
+
+    // The cookie's token when its signature holds.
+    fn cookie_token<B>(&self, req: &Request<B>) -> Option<String> {
+        let cookies = req.headers().get(header::COOKIE)?.to_str().ok()?;
+        utils::cookie::parse_cookie(cookies)
+            .remove(&self.cookie_name)
+            .filter(|v| self.is_signed(v))
+    }
+
+    // The token of this client, a new one when it has none yet.
+    fn token(&self, req: &HttpRequest, ctx: &mut Context) -> anyhow::Result<String> {
+        let current = match self.mode {
+            CsrfMode::Session => ctx
+                .session
+                .as_ref()
+                .ok_or_else(no_session)?
+                .get(CSRF_SESSION_KEY)?
+                .and_then(|v| v.as_str().map(|v| v.to_string())),
+            CsrfMode::DoubleSubmitCookie => self.cookie_token(req),
+        };
+        if let Some(token) = current {
+            return Ok(token);
+        }
+        let token = self.new_token();
+        if let (CsrfMode::Session, Some(session)) = (self.mode, ctx.session.as_mut()) {
+            session.set(CSRF_SESSION_KEY, json!(token))?;
+        }
+        Ok(token)
+    }
+
+    async fn submitted_token(
+        &self,
+        req: &mut HttpRequest,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<String>> {
+        // A header that isn't ASCII can't be the token, so it fails the check like a wrong one.
+        if let Some(v) = req.headers().get(self.header_name.as_str()) {
+            return Ok(v.to_str().ok().map(|v| v.to_string()));
+        }
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .is_some_and(|v| {
+                v.trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            });
+        if !is_form {
+            return Ok(None);
+        }
+        let form = BodyStream::with_limit(req, self.form_limit)?
+            .decompress()?
+            .form()
+            .await?;
+        let token = form
+            .iter()
+            .find(|(k, _)| *k == self.field_name)
+            .map(|(_, v)| v.clone());
+        ctx.any_map.set(context::SUBMITTED_FORM_KEY, form);
+        Ok(token)
+    }
+}
+
+// A setup mistake rather than a bad request: the details go to the log, not to the client.
+fn no_session() -> anyhow::Error {
+    println!(
+        "CsrfMiddleware: CsrfMode::Session needs a session provider and SessionMiddleware \
+         added before CsrfMiddleware"
+    );
+    HttpError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "CSRF protection is misconfigured",
+    )
+    .into()
+}
+
+// Compares in time that depends on the length only, so a guess can't be refined byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[async_trait]
+impl Middleware for CsrfMiddleware {
+    async fn pre_process(
+        &self,
+        req: &mut HttpRequest,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        let token = self.token(req, ctx)?;
+        ctx.any_map.set(context::CSRF_TOKEN_KEY, token.clone());
+
+        let safe = matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        );
+        let path = req.uri().path();
+        if safe || self.exempt.iter().any(|v| v.is_match(path)) {
+            return Ok(None);
+        }
+        // A token the client just got can't have been submitted yet.
+        let expected = match self.mode {
+            CsrfMode::Session => Some(token),
+            CsrfMode::DoubleSubmitCookie => self.cookie_token(req),
+        };
+        let submitted = self.submitted_token(req, ctx).await?;
+        match (expected, submitted) {
+            (Some(expected), Some(submitted))
+                if constant_time_eq(expected.as_bytes(), submitted.as_bytes()) =>
+            {
+                Ok(None)
+            }
+            _ => Err(HttpError::new(StatusCode::FORBIDDEN, "CSRF token missing or invalid").into()),
+        }
+    }
+
+    async fn post_process(
+        &self,
+        req: &mut HttpRequest,
+        res: &mut HttpResonse,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        if self.mode != CsrfMode::DoubleSubmitCookie {
+            return Ok(None);
+        }
+        let token = match ctx.csrf_token() {
+            Some(v) if self.cookie_token(req).as_deref() != Some(v) => v.to_string(),
+            _ => return Ok(None),
+        };
+        // Scripts read it to send the header, so it can't be HttpOnly.
+        let cookie = cookie::Cookie::build(self.cookie_name.clone(), token)
+            .path("/")
+            .same_site(cookie::SameSite::Strict)
+            .secure(ctx.is_secure())
+            .finish();
+        res.headers_mut()
+            .append(header::SET_COOKIE, cookie.to_string().parse()?);
+        Ok(None)
+    }
+}
+
+/// The headers [`SecurityHeadersMiddleware`] adds. Each setter takes None to leave the header
+/// out. In the Content-Security-Policy `{nonce}` is replaced with a fresh nonce per request,
+/// see [`Context::csp_nonce`].
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    hsts: Option<String>,
+    csp: Option<String>,
+    content_type_options: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+    opener_policy: Option<String>,
+    resource_policy: Option<String>,
+    embedder_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        let csp = [
+            "default-src 'self'",
+            "script-src 'self' 'nonce-{nonce}'",
+            "style-src 'self' 'unsafe-inline'",
+            "img-src 'self' data:",
+            "object-src 'none'",
+            "base-uri 'self'",
+            "form-action 'self'",
+            "frame-ancestors 'self'",
+        ]
+        .join("; ");
+        SecurityHeaders {
+            hsts: Some("max-age=31536000; includeSubDomains".to_string()),
+            csp: Some(csp),
+            content_type_options: Some("nosniff".to_string()),
+            frame_options: Some("SAMEORIGIN".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=()".to_string()),
+            opener_policy: Some("same-origin".to_string()),
+            resource_policy: Some("same-origin".to_string()),
+            // Breaks any cross-origin resource not opting in, so only on request.
+            embedder_policy: None,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Strict-Transport-Security, only sent on requests that came in over https.
+    pub fn hsts(mut self, value: Option<&str>) -> Self {
+        self.hsts = value.map(|v| v.to_string());
+        self
+    }
+
+    pub fn content_security_policy(mut self, value: Option<&str>) -> Self {
+        self.csp = value.map(|v| v.to_string());
+        self
+    }
+
+    pub fn content_type_options(mut self, value: Option<&str>) -> Self {
+        self.content_type_options = value.map(|v| v.to_string());
+        self
+    }
+
+    pub fn frame_options(mut self, value: Option<&str>) -> Self {
+        self.frame_options = value.map(|v| v.to_string());
+        self
+    }
+
+    pub fn referrer_policy(mut self, value: Option<&str>) -> Self {
+        self.referrer_policy = value.map(|v| v.to_string());
+        self
+    }
+
+    pub fn permissions_policy(mut self, value: Option<&str>) -> Self {
+        self.permissions_policy = value.map(|v| v.to_string());
+        self
+    }
+
+    /// Cross-Origin-Opener-Policy.
+    pub fn opener_policy(mut self, value: Option<&str>) -> Self {
+        self.opener_policy = value.map(|v| v.to_string());
+        self
+    }
+
+    /// Cross-Origin-Resource-Policy.
+    pub fn resource_policy(mut self, value: Option<&str>) -> Self {
+        self.resource_policy = value.map(|v| v.to_string());
+        self
+    }
+
+    /// Cross-Origin-Embedder-Policy, not sent by default.
+    pub fn embedder_policy(mut self, value: Option<&str>) -> Self {
+        self.embedder_policy = value.map(|v| v.to_string());
+        self
+    }
+
+    fn needs_nonce(&self) -> bool {
+        self.csp.as_deref().is_some_and(|v| v.contains("{nonce}"))
+    }
+}
+
+/// Adds headers that turn on browser protections, helmet-style. A header the view set itself
+/// is kept.
+pub struct SecurityHeadersMiddleware {
+    default: SecurityHeaders,
+    routes: Vec<(Regex, SecurityHeaders)>,
+}
+
+impl SecurityHeadersMiddleware {
+    pub fn new(headers: SecurityHeaders) -> Self {
+        SecurityHeadersMiddleware {
+            default: headers,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Sends `headers` instead of the default set for paths matching `path`, the first
+    /// matching route wins.
+    pub fn route(mut self, path: Regex, headers: SecurityHeaders) -> Self {
+        self.routes.push((path, headers));
+        self
+    }
+
+    fn headers_for(&self, req: &HttpRequest) -> &SecurityHeaders {
+        let path = req.uri().path();
+        self.routes
+            .iter()
+            .find(|(re, _)| re.is_match(path))
+            .map(|(_, v)| v)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[async_trait]
+impl Middleware for SecurityHeadersMiddleware {
+    async fn pre_process(
+        &self,
+        req: &mut HttpRequest,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        if self.headers_for(req).needs_nonce() {
+            let nonce = STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+            ctx.any_map.set(context::CSP_NONCE_KEY, nonce);
+        }
+        Ok(None)
+    }
+
+    async fn post_process(
+        &self,
+        req: &mut HttpRequest,
+        res: &mut HttpResonse,
+        ctx: &mut Context,
+    ) -> anyhow::Result<Option<HttpResonse>> {
+        let config = self.headers_for(req);
+        let csp = match (config.csp.as_ref(), ctx.csp_nonce()) {
+            (Some(csp), Some(nonce)) => Some(csp.replace("{nonce}", nonce)),
+            (csp, _) => csp.cloned(),
+        };
+        let hsts = match ctx.is_secure() {
+            true => config.hsts.as_ref(),
+            false => None,
+        };
+        let headers = [
+            (header::STRICT_TRANSPORT_SECURITY, hsts),
+            (header::CONTENT_SECURITY_POLICY, csp.as_ref()),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                config.content_type_options.as_ref(),
+            ),
+            (header::X_FRAME_OPTIONS, config.frame_options.as_ref()),
+            (header::REFERRER_POLICY, config.referrer_policy.as_ref()),
+            (
+                header::HeaderName::from_static("permissions-policy"),
+                config.permissions_policy.as_ref(),
+            ),
+            (
+                header::HeaderName::from_static("cross-origin-opener-policy"),
+                config.opener_policy.as_ref(),
+            ),
+            (
+                header::HeaderName::from_static("cross-origin-resource-policy"),
+                config.resource_policy.as_ref(),
+            ),
+            (
+                header::HeaderName::from_static("cross-origin-embedder-policy"),
+                config.embedder_policy.as_ref(),
+            ),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                if !res.headers().contains_key(&name) {
+                    res.headers_mut().insert(name, value.parse()?);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request, TestServer};
+    use hyper::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn proxies() -> ForwardedMiddleware {
+        ForwardedMiddleware::from_cidrs(&["10.0.0.0/8", "2001:db8:ffff::1"]).unwrap()
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.1"), ip("192.0.2.1"));
+        assert_eq!(parse_node("192.0.2.1:8080"), ip("192.0.2.1"));
+        assert_eq!(parse_node("\"[2001:db8::1]:4711\""), ip("2001:db8::1"));
+        assert_eq!(parse_node("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("[2001:db8::1"), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn untrusted_peer_is_ignored() {
+        let h = headers(&[
+            ("host", "example.com"),
+            ("forwarded", "for=198.51.100.7;proto=https;host=evil.com"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ]);
+        let info = proxies().resolve(&h, None, ip("192.0.2.1"));
+        assert_eq!(info.client_ip, ip("192.0.2.1"));
+        assert_eq!(info.scheme, None);
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn forwarded_picks_rightmost_untrusted_hop() {
+        let h = headers(&[
+            ("host", "internal:8080"),
+            (
+                "forwarded",
+                "for=1.1.1.1;proto=http, for=\"[2001:db8::7]:1234\";proto=https;host=example.com",
+            ),
+            ("forwarded", "for=10.0.0.2;proto=http;host=internal"),
+        ]);
+        let info = proxies().resolve(&h, None, ip("10.0.0.1"));
+        assert_eq!(info.client_ip, ip("2001:db8::7"));
+        assert_eq!(info.scheme.as_deref(), Some("https"));
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn forwarded_all_trusted_uses_leftmost() {
+        let h = headers(&[("forwarded", "for=10.0.0.3, for=10.0.0.2")]);
+        let info = proxies().resolve(&h, None, ip("10.0.0.1"));
+        assert_eq!(info.client_ip, ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn forwarded_unknown_node_stops_the_chain() {
+        let h = headers(&[("forwarded", "for=198.51.100.7, for=unknown, for=10.0.0.2")]);
+        let info = proxies().resolve(&h, None, ip("10.0.0.1"));
+        assert_eq!(info.client_ip, None);
+    }
+
+    #[test]
+    fn x_forwarded_takes_proto_and_host_from_the_client_hop() {
+        // The client spoofed one entry per header, the edge proxy (trusted) appended its view.
+        let h = headers(&[
+            ("x-forwarded-for", "6.6.6.6, 198.51.100.7"),
+            ("x-forwarded-proto", "http, https"),
+            ("x-forwarded-host", "evil.com, example.com"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "internal"),
+        ]);
+        let info = proxies().resolve(&h, None, ip("10.0.0.1"));
+        assert_eq!(info.client_ip, ip("198.51.100.7"));
+        assert_eq!(info.scheme.as_deref(), Some("https"));
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn x_forwarded_without_matching_entries() {
+        // Only the nearest proxy recorded proto, the client's hop has none.
+        let h = headers(&[
+            ("host", "example.com"),
+            ("x-forwarded-for", "198.51.100.7, 10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        let info = proxies().resolve(&h, None, ip("10.0.0.1"));
+        assert_eq!(info.client_ip, ip("198.51.100.7"));
+        assert_eq!(info.scheme, None);
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn x_forwarded_proto_without_for() {
+        let h = headers(&[
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+            ("x-forwarded-prefix", "/api/"),
+        ]);
+        let info = proxies().resolve(&h, None, ip("2001:db8:ffff::1"));
+        assert_eq!(info.client_ip, ip("2001:db8:ffff::1"));
+        assert_eq!(info.scheme.as_deref(), Some("https"));
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+        assert_eq!(info.prefix.as_deref(), Some("/api"));
+    }
+
+    #[test]
+    fn malformed_values_are_ignored() {
+        let h = headers(&[
+            ("forwarded", "for;proto=ftp;=;host"),
+            ("x-forwarded-prefix", "api"),
+        ]);
+        let info = proxies().resolve(&h, Some("example.com".to_string()), ip("10.0.0.1"));
+        assert_eq!(info.client_ip, None);
+        assert_eq!(info.scheme, None);
+        assert_eq!(info.host.as_deref(), Some("example.com"));
+        assert_eq!(info.prefix, None);
+    }
+
+    #[test]
+    fn non_ascii_host_is_ignored() {
+        let mut h = HeaderMap::new();
+        h.insert(
+            header::HOST,
+            HeaderValue::from_bytes(b"ex\xe4mple.com").unwrap(),
+        );
+        let info = proxies().resolve(&h, None, ip("192.0.2.1"));
+        assert_eq!(info.host, None);
+    }
+
+    #[test]
+    fn unix_socket_peer() {
+        let h = headers(&[("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(proxies().resolve(&h, None, None).client_ip, None);
+        let info = proxies().trust_unix_socket(true).resolve(&h, None, None);
+        assert_eq!(info.client_ip, ip("198.51.100.7"));
+    }
+
+    fn text_response(len: usize, content_type: &str) -> HttpResonse {
+        response::build_response("a".repeat(len), StatusCode::OK, content_type).unwrap()
+    }
+
+    fn gzip_request() -> Request<()> {
+        Request::get("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap()
+    }
+
+    fn vary(res: &HttpResonse) -> Vec<&str> {
+        res.headers()
+            .get_all(header::VARY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn compresses_large_text() {
+        let mut res = text_response(2000, "text/html; charset=utf-8");
+        res.headers_mut()
+            .insert(header::ETAG, "\"abc\"".parse().unwrap());
+        res.headers_mut()
+            .insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+        res.headers_mut()
+            .insert(header::CONTENT_LENGTH, 2000.into());
+        CompressionMiddleware::new()
+            .compress(&gzip_request(), &mut res)
+            .unwrap();
+        let headers = res.headers();
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[header::ETAG], "W/\"abc\"");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+        assert!(!headers.contains_key(header::ACCEPT_RANGES));
+        assert_eq!(vary(&res), ["accept-encoding"]);
+    }
+
+    #[test]
+    fn weak_etag_stays_as_is() {
+        let mut res = text_response(2000, "application/json");
+        res.headers_mut()
+            .insert(header::ETAG, "W/\"abc\"".parse().unwrap());
+        CompressionMiddleware::new()
+            .compress(&gzip_request(), &mut res)
+            .unwrap();
+        assert_eq!(res.headers()[header::ETAG], "W/\"abc\"");
+    }
+
+    #[test]
+    fn skips_small_responses() {
+        let mut res = text_response(100, "text/plain");
+        CompressionMiddleware::new()
+            .compress(&gzip_request(), &mut res)
+            .unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(vary(&res).is_empty());
+
+        let mut res = text_response(100, "text/plain");
+        CompressionMiddleware::new()
+            .min_size(10)
+            .compress(&gzip_request(), &mut res)
+            .unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    }
+
+    #[test]
+    fn picks_by_content_type() {
+        let m = CompressionMiddleware::new();
+        for (content_type, compressed) in [
+            ("text/css", true),
+            ("application/vnd.api+json", true),
+            ("image/svg+xml", true),
+            ("image/png", false),
+            ("application/octet-stream", false),
+            ("text/event-stream", false),
+        ] {
+            let mut res = text_response(2000, content_type);
+            m.compress(&gzip_request(), &mut res).unwrap();
+            assert_eq!(
+                res.headers().contains_key(header::CONTENT_ENCODING),
+                compressed,
+                "{}",
+                content_type
+            );
+        }
+
+        let m = CompressionMiddleware::new().content_types(&["image/png"]);
+        let mut res = text_response(2000, "image/png");
+        m.compress(&gzip_request(), &mut res).unwrap();
+        assert!(res.headers().contains_key(header::CONTENT_ENCODING));
+        let mut res = text_response(2000, "text/html");
+        m.compress(&gzip_request(), &mut res).unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn leaves_encoded_partial_and_no_transform_alone() {
+        let m = CompressionMiddleware::new();
+        let mut res = text_response(2000, "text/plain");
+        res.headers_mut()
+            .insert(header::CONTENT_ENCODING, "br".parse().unwrap());
+        m.compress(&gzip_request(), &mut res).unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+
+        let mut res = text_response(2000, "text/plain");
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            "public, no-transform".parse().unwrap(),
+        );
+        m.compress(&gzip_request(), &mut res).unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+        let mut res = text_response(2000, "text/plain");
+        *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+        m.compress(&gzip_request(), &mut res).unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+        let head = Request::head("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let mut res = text_response(2000, "text/plain");
+        m.compress(&head, &mut res).unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn varies_even_without_acceptable_encoding() {
+        let req = Request::get("/")
+            .header(header::ACCEPT_ENCODING, "identity")
+            .body(())
+            .unwrap();
+        let mut res = text_response(2000, "text/plain");
+        res.headers_mut()
+            .insert(header::VARY, "Origin".parse().unwrap());
+        CompressionMiddleware::new()
+            .compress(&req, &mut res)
+            .unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(vary(&res), ["Origin", "accept-encoding"]);
+
+        let mut res = text_response(2000, "text/plain");
+        res.headers_mut()
+            .insert(header::VARY, "Accept-Encoding, Origin".parse().unwrap());
+        CompressionMiddleware::new()
+            .compress(&gzip_request(), &mut res)
+            .unwrap();
+        assert_eq!(vary(&res), ["Accept-Encoding, Origin"]);
+    }
+
+    #[test]
+    fn respects_disabled_encodings() {
+        let req = Request::get("/")
+            .header(header::ACCEPT_ENCODING, "br, zstd, gzip;q=0.1")
+            .body(())
+            .unwrap();
+        let mut res = text_response(2000, "text/plain");
+        CompressionMiddleware::new()
+            .brotli(false)
+            .zstd(false)
+            .compress(&req, &mut res)
+            .unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let mut res = text_response(2000, "text/plain");
+        CompressionMiddleware::new()
+            .compress(&req, &mut res)
+            .unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+    }
+
+    fn cors_request(method: Method, origin: Option<&str>, extra: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri("/api");
+        if let Some(origin) = origin {
+            builder = builder.header(header::ORIGIN, origin);
+        }
+        for (name, value) in extra {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn allowed(cors: &CorsMiddleware, origin: &str) -> bool {
+        let req = cors_request(Method::GET, Some(origin), &[]);
+        cors.allowed_origin(&req).is_some()
+    }
+
+    #[test]
+    fn matches_origins() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://App.example.com/")
+            .allow_origin("https://*.example.org")
+            .allow_origin_regex(Regex::new(r"^http://localhost:\d+$").unwrap())
+            .allow_origin_fn(|v| v == "null");
+        assert!(allowed(&cors, "https://app.example.com"));
+        assert!(allowed(&cors, "HTTPS://APP.EXAMPLE.COM"));
+        assert!(!allowed(&cors, "http://app.example.com"));
+        assert!(!allowed(&cors, "https://app.example.com.evil.com"));
+
+        assert!(allowed(&cors, "https://a.example.org"));
+        assert!(allowed(&cors, "https://a.b-c.example.org"));
+        assert!(!allowed(&cors, "https://example.org"));
+        assert!(!allowed(&cors, "https://.example.org"));
+        assert!(!allowed(&cors, "https://evilexample.org"));
+        assert!(!allowed(&cors, "https://evil.com/.example.org"));
+        assert!(!allowed(&cors, "https://evil.com:1@x.example.org"));
+        assert!(!allowed(&cors, "https://a.example.org:8443"));
+
+        assert!(allowed(&cors, "http://localhost:3000"));
+        assert!(!allowed(&cors, "http://localhost"));
+        assert!(allowed(&cors, "null"));
+
+        assert!(!allowed(&CorsMiddleware::new(), "https://app.example.com"));
+    }
+
+    #[test]
+    fn any_origin_gets_a_star_without_vary() {
+        let cors = CorsMiddleware::new().allow_origin("*");
+        let req = cors_request(Method::GET, Some("https://x.test"), &[]);
+        let mut res = text_response(10, "text/plain");
+        cors.add_headers(&req, &mut res).unwrap();
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(vary(&res).is_empty());
+    }
+
+    #[test]
+    fn listed_origins_are_echoed_with_vary() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://app.example.com")
+            .allow_credentials(true)
+            .expose_headers(&["X-Request-Id"]);
+        let req = cors_request(Method::GET, Some("https://app.example.com"), &[]);
+        let mut res = text_response(10, "text/plain");
+        cors.add_headers(&req, &mut res).unwrap();
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "x-request-id"
+        );
+        assert_eq!(vary(&res), ["origin"]);
+
+        // Other origins and same-origin requests still vary, a cache may not mix them up.
+        for origin in [Some("https://evil.test"), None] {
+            let req = cors_request(Method::GET, origin, &[]);
+            let mut res = text_response(10, "text/plain");
+            cors.add_headers(&req, &mut res).unwrap();
+            assert!(!res
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert!(!res
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+            assert_eq!(vary(&res), ["origin"]);
+        }
+    }
+
+    #[test]
+    fn answers_preflights() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://app.example.com")
+            .allow_methods(&[Method::GET, Method::PUT])
+            .max_age(Duration::from_secs(600));
+        let req = cors_request(
+            Method::OPTIONS,
+            Some("https://app.example.com"),
+            &[
+                ("access-control-request-method", "PUT"),
+                ("access-control-request-headers", "content-type, x-token"),
+            ],
+        );
+        let res = cors.short_circuit(&req).unwrap().unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-token"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        assert_eq!(
+            vary(&res),
+            [
+                "origin",
+                "access-control-request-method",
+                "access-control-request-headers"
+            ]
+        );
+    }
+
+    #[test]
+    fn preflight_with_fixed_headers_and_unknown_origin() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://app.example.com")
+            .allow_headers(&["Content-Type"]);
+        let req = cors_request(
+            Method::OPTIONS,
+            Some("https://app.example.com"),
+            &[
+                ("access-control-request-method", "POST"),
+                ("access-control-request-headers", "x-token"),
+            ],
+        );
+        let res = cors.short_circuit(&req).unwrap().unwrap();
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+        assert_eq!(vary(&res), ["origin", "access-control-request-method"]);
+
+        // Still answered, but without the headers that would let the browser go ahead.
+        let req = cors_request(
+            Method::OPTIONS,
+            Some("https://evil.test"),
+            &[("access-control-request-method", "POST")],
+        );
+        let res = cors.short_circuit(&req).unwrap().unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+    }
+
+    #[test]
+    fn only_preflights_short_circuit() {
+        let cors = CorsMiddleware::new().allow_origin("*");
+        let plain_options = cors_request(Method::OPTIONS, Some("https://x.test"), &[]);
+        assert!(cors.short_circuit(&plain_options).unwrap().is_none());
+        let no_origin = cors_request(
+            Method::OPTIONS,
+            None,
+            &[("access-control-request-method", "GET")],
+        );
+        assert!(cors.short_circuit(&no_origin).unwrap().is_none());
+        let get = cors_request(
+            Method::GET,
+            Some("https://x.test"),
+            &[("access-control-request-method", "GET")],
+        );
+        assert!(cors.short_circuit(&get).unwrap().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "allow_credentials")]
+    fn any_origin_then_credentials_panics() {
+        let _ = CorsMiddleware::new()
+            .allow_origin("*")
+            .allow_credentials(true);
+    }
+
+    #[test]
+    #[should_panic(expected = "allow_credentials")]
+    fn credentials_then_any_origin_panics() {
+        let _ = CorsMiddleware::new()
+            .allow_credentials(true)
+            .allow_origin("*");
+    }
+
+    #[test]
+    fn credentials_with_subdomain_wildcard() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://*.example.com")
+            .allow_credentials(true);
+        let req = cors_request(Method::GET, Some("https://a.example.com"), &[]);
+        assert_eq!(
+            cors.allowed_origin(&req).as_deref(),
+            Some("https://a.example.com")
+        );
+        // Turning credentials off again is always fine.
+        let _ = CorsMiddleware::new()
+            .allow_origin("*")
+            .allow_credentials(false);
+    }
+
+    struct Probe;
+
+    #[async_trait]
+    impl crate::view::View for Probe {
+        async fn call(
+            &self,
+            req: &mut HttpRequest,
+            ctx: &mut Context,
+        ) -> anyhow::Result<HttpResonse> {
+            let session_token = match ctx.session {
+                Some(ref session) => session.get(CSRF_SESSION_KEY)?,
+                None => None,
+            };
+            let mut res = response::ok_json(json!({
+                "csrf": ctx.csrf_token(),
+                "session_csrf": session_token,
+                "nonce": ctx.csp_nonce(),
+            }))?;
+            if req.uri().path() == "/own" {
+                res.headers_mut()
+                    .insert(header::X_FRAME_OPTIONS, "DENY".parse()?);
+            }
+            Ok(res)
+        }
+        fn methods(&self) -> Vec<Method> {
+            vec![Method::GET, Method::HEAD, Method::POST]
+        }
+        fn re_path(&self) -> Regex {
+            Regex::new("^/(probe|own)$").unwrap()
+        }
+    }
+
+    async fn serve(middlewares: Vec<std::sync::Arc<dyn Middleware>>) -> std::net::SocketAddr {
+        let mut app = crate::SimpleApi::new();
+        app.add_route(Probe);
+        for m in middlewares {
+            app.add_middleware(m);
+        }
+        TestServer::start(app).await.addr
+    }
+
+    async fn csrf_server() -> std::net::SocketAddr {
+        let csrf = CsrfMiddleware::new(CsrfMode::DoubleSubmitCookie);
+        serve(vec![std::sync::Arc::new(csrf)]).await
+    }
+
+    // The token the server hands out on a GET.
+    async fn fetch_token(addr: std::net::SocketAddr) -> String {
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        let cookie = reply.header("set-cookie").unwrap();
+        let token = cookie
+            .strip_prefix("csrf_token=")
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        assert_eq!(reply.json()["csrf"], token.as_str());
+        token
+    }
+
+    #[tokio::test]
+    async fn csrf_lets_safe_methods_through() {
+        let addr = csrf_server().await;
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        assert_eq!(reply.status, 200);
+        let cookie = reply.header("set-cookie").unwrap();
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(!cookie.contains("HttpOnly"));
+        assert_eq!(
+            request(addr, b"HEAD /probe HTTP/1.1", b"").await.status,
+            200
+        );
+
+        // A client that already has a valid cookie isn't sent a new one.
+        let token = fetch_token(addr).await;
+        let head = format!("GET /probe HTTP/1.1\r\nCookie: csrf_token={}", token);
+        let reply = request(addr, head.as_bytes(), b"").await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.header("set-cookie"), None);
+        assert_eq!(reply.json()["csrf"], token.as_str());
+    }
+
+    #[tokio::test]
+    async fn csrf_checks_the_header() {
+        let addr = csrf_server().await;
+        let token = fetch_token(addr).await;
+        let other = fetch_token(addr).await;
+        assert_ne!(token, other);
+
+        let cases: Vec<(String, u16)> = vec![
+            (String::new(), 403),
+            (format!("\r\nCookie: csrf_token={}", token), 403),
+            (format!("\r\nX-CSRF-Token: {}", token), 403),
+            (
+                format!(
+                    "\r\nCookie: csrf_token={}\r\nX-CSRF-Token: {}",
+                    token, other
+                ),
+                403,
+            ),
+            (
+                format!(
+                    "\r\nCookie: csrf_token={}\r\nX-CSRF-Token: {}",
+                    token, token
+                ),
+                200,
+            ),
+        ];
+        for (extra, status) in cases {
+            let head = format!("POST /probe HTTP/1.1{}", extra);
+            assert_eq!(
+                request(addr, head.as_bytes(), b"").await.status,
+                status,
+                "{}",
+                extra
+            );
+        }
+
+        let mut head = format!("POST /probe HTTP/1.1\r\nCookie: csrf_token={}", token).into_bytes();
+        head.extend_from_slice(b"\r\nX-CSRF-Token: \xff\xfe");
+        assert_eq!(request(addr, &head, b"").await.status, 403);
+    }
+
+    #[tokio::test]
+    async fn csrf_accepts_the_form_field() {
+        let addr = csrf_server().await;
+        let token = fetch_token(addr).await;
+        let head = format!(
+            "POST /probe HTTP/1.1\r\nCookie: csrf_token={}\r\n\
+             Content-Type: application/x-www-form-urlencoded; charset=utf-8",
+            token
+        );
+        let body = format!("name=a&csrf_token={}", token);
+        assert_eq!(
+            request(addr, head.as_bytes(), body.as_bytes()).await.status,
+            200
+        );
+        assert_eq!(request(addr, head.as_bytes(), b"name=a").await.status, 403);
+        assert_eq!(
+            request(addr, head.as_bytes(), b"csrf_token=nope")
+                .await
+                .status,
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn csrf_rejects_unsigned_cookies() {
+        let addr = csrf_server().await;
+        let foreign = fetch_token(csrf_server().await).await;
+        let (value, _) = foreign.split_once('.').unwrap();
+        for token in [foreign.as_str(), value, "x.y"] {
+            let head = format!(
+                "POST /probe HTTP/1.1\r\nCookie: csrf_token={}\r\nX-CSRF-Token: {}",
+                token, token
+            );
+            assert_eq!(request(addr, head.as_bytes(), b"").await.status, 403);
+        }
+        // A GET replaces the bad cookie with one of this server.
+        let head = format!("GET /probe HTTP/1.1\r\nCookie: csrf_token={}", foreign);
+        let reply = request(addr, head.as_bytes(), b"").await;
+        assert!(reply.header("set-cookie").is_some());
+        assert_ne!(reply.json()["csrf"], foreign.as_str());
+    }
+
+    #[test]
+    fn csrf_tokens_are_signed_with_the_key() {
+        let key = || hmac::Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        let a = CsrfMiddleware::new(CsrfMode::DoubleSubmitCookie).key(key());
+        let b = CsrfMiddleware::new(CsrfMode::DoubleSubmitCookie).key(key());
+        let token = a.new_token();
+        assert!(a.is_signed(&token));
+        assert!(b.is_signed(&token));
+        assert!(!CsrfMiddleware::new(CsrfMode::DoubleSubmitCookie).is_signed(&token));
+        let (value, signature) = token.split_once('.').unwrap();
+        assert!(!a.is_signed(value));
+        assert!(!a.is_signed(&format!("{}x.{}", value, signature)));
+        assert!(!a.is_signed(&format!("{}.!{}", value, signature)));
+    }
+
+    async fn security_server(headers: SecurityHeadersMiddleware) -> std::net::SocketAddr {
+        let forwarded = ForwardedMiddleware::from_cidrs(&["127.0.0.1/32"]).unwrap();
+        serve(vec![
+            std::sync::Arc::new(forwarded),
+            std::sync::Arc::new(headers),
+        ])
+        .await
+    }
+
+    #[tokio::test]
+    async fn csp_nonce_is_fresh_per_request() {
+        let addr = security_server(SecurityHeadersMiddleware::new(SecurityHeaders::new())).await;
+        let mut nonces = Vec::new();
+        for _ in 0..3 {
+            let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+            let nonce = reply.json()["nonce"].as_str().unwrap().to_string();
+            let csp = reply.header("content-security-policy").unwrap();
+            assert!(csp.contains(&format!("script-src 'self' 'nonce-{}'", nonce)));
+            assert!(!csp.contains("{nonce}"));
+            assert!(!nonces.contains(&nonce));
+            nonces.push(nonce);
+        }
+
+        // No nonce is made when the policy has no place for it.
+        let headers = SecurityHeaders::new().content_security_policy(Some("default-src 'self'"));
+        let addr = security_server(SecurityHeadersMiddleware::new(headers)).await;
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        assert!(reply.json()["nonce"].is_null());
+        assert_eq!(
+            reply.header("content-security-policy"),
+            Some("default-src 'self'")
+        );
+    }
+
+    #[tokio::test]
+    async fn hsts_only_over_https() {
+        let addr = security_server(SecurityHeadersMiddleware::new(SecurityHeaders::new())).await;
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        assert_eq!(reply.header("strict-transport-security"), None);
+        assert_eq!(reply.header("x-content-type-options"), Some("nosniff"));
+
+        let head = b"GET /probe HTTP/1.1\r\nX-Forwarded-Proto: https";
+        let reply = request(addr, head, b"").await;
+        assert_eq!(
+            reply.header("strict-transport-security"),
+            Some("max-age=31536000; includeSubDomains")
+        );
+
+        let headers = SecurityHeaders::new().hsts(None);
+        let addr = security_server(SecurityHeadersMiddleware::new(headers)).await;
+        let reply = request(addr, head, b"").await;
+        assert_eq!(reply.header("strict-transport-security"), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_headers_the_view_set() {
+        let addr = security_server(SecurityHeadersMiddleware::new(SecurityHeaders::new())).await;
+        let reply = request(addr, b"GET /own HTTP/1.1", b"").await;
+        assert_eq!(reply.head.matches("x-frame-options").count(), 1);
+        assert_eq!(reply.header("x-frame-options"), Some("DENY"));
+        assert_eq!(
+            reply.header("referrer-policy"),
+            Some("strict-origin-when-cross-origin")
+        );
+
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        assert_eq!(reply.header("x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(reply.header("cross-origin-embedder-policy"), None);
+    }
+
+    #[tokio::test]
+    async fn routes_pick_their_own_headers() {
+        let headers = SecurityHeadersMiddleware::new(SecurityHeaders::new()).route(
+            Regex::new("^/own$").unwrap(),
+            SecurityHeaders::new()
+                .content_security_policy(None)
+                .embedder_policy(Some("require-corp")),
+        );
+        let addr = security_server(headers).await;
+        let reply = request(addr, b"GET /own HTTP/1.1", b"").await;
+        assert_eq!(reply.header("content-security-policy"), None);
+        assert!(reply.json()["nonce"].is_null());
+        assert_eq!(
+            reply.header("cross-origin-embedder-policy"),
+            Some("require-corp")
+        );
+        let reply = request(addr, b"GET /probe HTTP/1.1", b"").await;
+        assert!(reply.header("content-security-policy").is_some());
+    }
+
+    async fn session_csrf_server() -> std::net::SocketAddr {
+        let mut app = crate::SimpleApi::new();
+        app.add_route(Probe);
+        let provider = crate::session::CookieSessionProvider::from_slice(b"session key").unwrap();
+        app.set_session_provider(std::sync::Arc::new(provider))
+            .await;
+        app.add_middleware(std::sync::Arc::new(SessionMiddleware));
+        app.add_middleware(std::sync::Arc::new(CsrfMiddleware::new(CsrfMode::Session)));
+        TestServer::start(app).await.addr
+    }
+
+    // The session cookie and CSRF token of a new session.
+    async fn new_session(addr: std::net::SocketAddr) -> (String, String) {
+        let res = request(addr, "GET /probe HTTP/1.1", b"").await;
+        assert_eq!(res.status, 200);
+        let cookie = res.header("set-cookie").unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        assert!(cookie.starts_with("signed_session="), "{}", cookie);
+        let token = res.json()["csrf"].as_str().unwrap().to_string();
+        assert_eq!(res.json()["session_csrf"], token.as_str());
+        (cookie, token)
+    }
+
+    #[tokio::test]
+    async fn csrf_session_mode() {
+        let addr = session_csrf_server().await;
+        let (cookie, token) = new_session(addr).await;
+        let (other_cookie, other_token) = new_session(addr).await;
+        assert_ne!(token, other_token);
+
+        // The token stays the same for the session.
+        let head = format!("GET /probe HTTP/1.1\r\nCookie: {}", cookie);
+        assert_eq!(
+            request(addr, &head, b"").await.json()["csrf"],
+            token.as_str()
+        );
+
+        let post = |cookie: &str, token: &str| {
+            format!(
+                "POST /probe HTTP/1.1\r\nCookie: {}\r\nX-CSRF-Token: {}",
+                cookie, token
+            )
+        };
+        assert_eq!(request(addr, post(&cookie, &token), b"").await.status, 200);
+        assert_eq!(
+            request(addr, post(&other_cookie, &other_token), b"")
+                .await
+                .status,
+            200
+        );
+        assert_eq!(
+            request(addr, post(&other_cookie, &token), b"").await.status,
+            403
+        );
+        assert_eq!(
+            request(addr, post(&cookie, &other_token), b"").await.status,
+            403
+        );
+        let no_session = format!("POST /probe HTTP/1.1\r\nX-CSRF-Token: {}", token);
+        assert_eq!(request(addr, no_session, b"").await.status, 403);
+        let no_token = format!("POST /probe HTTP/1.1\r\nCookie: {}", cookie);
+        assert_eq!(request(addr, &no_token, b"").await.status, 403);
+
+        let form = format!(
+            "POST /probe HTTP/1.1\r\nCookie: {}\r\n\
+             Content-Type: application/x-www-form-urlencoded",
+            cookie
+        );
+        let body = format!("a=1&csrf_token={}", token);
+        assert_eq!(request(addr, &form, body.as_bytes()).await.status, 200);
+        let body = format!("a=1&csrf_token={}", other_token);
+        assert_eq!(request(addr, &form, body.as_bytes()).await.status, 403);
+    }
+
+    #[tokio::test]
+    async fn csrf_skips_exempt_paths() {
+        let csrf =
+            CsrfMiddleware::new(CsrfMode::DoubleSubmitCookie).exempt(Regex::new("^/own$").unwrap());
+        let addr = serve(vec![std::sync::Arc::new(csrf)]).await;
+        assert_eq!(request(addr, "POST /own HTTP/1.1", b"").await.status, 200);
+        assert_eq!(request(addr, "POST /probe HTTP/1.1", b"").await.status, 403);
+    }
+
+    #[tokio::test]
+    async fn csrf_session_mode_without_sessions() {
+        let csrf = CsrfMiddleware::new(CsrfMode::Session);
+        let addr = serve(vec![std::sync::Arc::new(csrf)]).await;
+        for head in ["GET /probe HTTP/1.1", "POST /probe HTTP/1.1"] {
+            let res = request(addr, head, b"").await;
+            assert_eq!(res.status, 500);
+            assert_eq!(res.text(), "CSRF protection is misconfigured");
+        }
+    }
+}
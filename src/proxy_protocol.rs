@@ -0,0 +1,284 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::io::{AsyncReadExt, BufReader};
+
+use crate::server::BoxedIo;
+
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // including the CRLF
+
+/// Addresses a proxy reported in the PROXY protocol header. Both are None when the proxy
+/// sent a LOCAL (v2) or UNKNOWN (v1) header, e.g. for its own health checks.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyInfo {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+// Reads the header in front of the proxied stream. The returned stream continues right after
+// it, whatever the client sent next is still buffered inside.
+pub(crate) async fn read_header(
+    io: BoxedIo,
+    timeout: Duration,
+) -> anyhow::Result<(BoxedIo, ProxyInfo)> {
+    let mut reader = BufReader::new(io);
+    let info = tokio::time::timeout(timeout, parse_header(&mut reader))
+        .await
+        .map_err(|_| anyhow!("Timed out reading PROXY protocol header"))??;
+    Ok((Box::new(reader), info))
+}
+
+async fn parse_header(reader: &mut BufReader<BoxedIo>) -> anyhow::Result<ProxyInfo> {
+    // Both versions are at least 12 bytes long, "PROXY UNKNOWN\r\n" being the shortest.
+    let mut head = [0u8; 12];
+    reader.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        parse_v2(reader).await
+    } else if head.starts_with(V1_PREFIX) {
+        parse_v1(reader, &head).await
+    } else {
+        Err(anyhow!("Missing PROXY protocol header"))
+    }
+}
+
+async fn parse_v1(reader: &mut BufReader<BoxedIo>, head: &[u8]) -> anyhow::Result<ProxyInfo> {
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyInfo::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let src = src.parse::<IpAddr>()?;
+            let dst = dst.parse::<IpAddr>()?;
+            if src.is_ipv4() != (*family == "TCP4") || dst.is_ipv4() != (*family == "TCP4") {
+                return Err(anyhow!(
+                    "PROXY protocol v1 address doesn't match {}",
+                    family
+                ));
+            }
+            Ok(ProxyInfo {
+                source: Some(SocketAddr::new(src, parse_port(src_port)?)),
+                destination: Some(SocketAddr::new(dst, parse_port(dst_port)?)),
+            })
+        }
+        _ => Err(anyhow!("Malformed PROXY protocol v1 header")),
+    }
+}
+
+fn parse_port(s: &str) -> anyhow::Result<u16> {
+    // The spec forbids leading zeros, which u16::from_str would accept.
+    if s.len() > 1 && s.starts_with('0') {
+        return Err(anyhow!("Malformed PROXY protocol v1 port"));
+    }
+    Ok(s.parse::<u16>()?)
+}
+
+async fn parse_v2(reader: &mut BufReader<BoxedIo>) -> anyhow::Result<ProxyInfo> {
+    let ver_cmd = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await? as usize;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+
+    if ver_cmd >> 4 != 0x2 {
+        return Err(anyhow!("Unsupported PROXY protocol version"));
+    }
+    match ver_cmd & 0x0f {
+        0x0 => return Ok(ProxyInfo::default()), // LOCAL
+        0x1 => (),                              // PROXY
+        _ => return Err(anyhow!("Unsupported PROXY protocol v2 command")),
+    }
+
+    // Low nibble is the transport: 0x0 UNSPEC, 0x1 STREAM, 0x2 DGRAM. Only an UNSPEC family
+    // may come with an UNSPEC transport, HTTP never runs over datagrams.
+    match (family >> 4, family & 0x0f) {
+        (0x0, 0x0) | (_, 0x1) => (),
+        _ => return Err(anyhow!("Unsupported PROXY protocol v2 transport")),
+    }
+
+    // Anything after the addresses is TLVs, which we don't use.
+    let (source, destination) = match family >> 4 {
+        0x1 => {
+            let b = payload
+                .get(..12)
+                .ok_or(anyhow!("Truncated PROXY protocol v2 IPv4 addresses"))?;
+            let src = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+            let dst = Ipv4Addr::new(b[4], b[5], b[6], b[7]);
+            (
+                SocketAddr::new(src.into(), u16::from_be_bytes([b[8], b[9]])),
+                SocketAddr::new(dst.into(), u16::from_be_bytes([b[10], b[11]])),
+            )
+        }
+        0x2 => {
+            let b = payload
+                .get(..36)
+                .ok_or(anyhow!("Truncated PROXY protocol v2 IPv6 addresses"))?;
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&b[0..16])?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&b[16..32])?);
+            (
+                SocketAddr::new(src.into(), u16::from_be_bytes([b[32], b[33]])),
+                SocketAddr::new(dst.into(), u16::from_be_bytes([b[34], b[35]])),
+            )
+        }
+        // AF_UNSPEC and AF_UNIX carry no IP addresses.
+        0x0 | 0x3 => return Ok(ProxyInfo::default()),
+        _ => return Err(anyhow!("Unsupported PROXY protocol v2 address family")),
+    };
+    Ok(ProxyInfo {
+        source: Some(source),
+        destination: Some(destination),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(header: &[u8]) -> anyhow::Result<(ProxyInfo, Vec<u8>)> {
+        let mut data = header.to_vec();
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let io = Box::new(std::io::Cursor::new(data)) as BoxedIo;
+        let (mut io, info) = read_header(io, Duration::from_secs(1)).await?;
+        let mut rest = Vec::new();
+        io.read_to_end(&mut rest).await?;
+        Ok((info, rest))
+    }
+
+    fn v2(ver_cmd: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(ver_cmd);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (info, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(info.source, addr("192.0.2.1:56324"));
+        assert_eq!(info.destination, addr("198.51.100.2:443"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (info, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(info.source, addr("[2001:db8::1]:56324"));
+        assert_eq!(info.destination, addr("[2001:db8::2]:443"));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (info, rest) = parse(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert!(info.source.is_none() && info.destination.is_none());
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 70000 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 443\r\n",
+        ] {
+            assert!(parse(header).await.is_err(), "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(200, b'x');
+        header.extend_from_slice(b"\r\n");
+        assert!(parse(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_tcp4() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let (info, rest) = parse(&v2(0x21, 0x11, &payload)).await.unwrap();
+        assert_eq!(info.source, addr("192.0.2.1:56324"));
+        assert_eq!(info.destination, addr("198.51.100.2:443"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_tcp6_with_tlvs() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        payload.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]); // PP2_TYPE_NOOP
+        let (info, rest) = parse(&v2(0x21, 0x21, &payload)).await.unwrap();
+        assert_eq!(info.source, addr("[2001:db8::1]:56324"));
+        assert_eq!(info.destination, addr("[2001:db8::2]:443"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let payload = [127, 0, 0, 1, 127, 0, 0, 1, 0, 80, 0, 80];
+        let (info, _) = parse(&v2(0x20, 0x11, &payload)).await.unwrap();
+        assert!(info.source.is_none() && info.destination.is_none());
+        let (info, _) = parse(&v2(0x20, 0x00, &[])).await.unwrap();
+        assert!(info.source.is_none());
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_bad_family_and_transport() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb];
+        assert!(parse(&v2(0x21, 0x12, &payload)).await.is_err()); // TCP4 over DGRAM
+        assert!(parse(&v2(0x21, 0x10, &payload)).await.is_err()); // INET, UNSPEC transport
+        assert!(parse(&v2(0x21, 0x13, &payload)).await.is_err());
+        assert!(parse(&v2(0x21, 0x41, &payload)).await.is_err());
+        assert!(parse(&v2(0x22, 0x11, &payload)).await.is_err()); // unknown command
+        assert!(parse(&v2(0x11, 0x11, &payload)).await.is_err()); // version 1 in binary form
+    }
+
+    #[tokio::test]
+    async fn v2_truncated() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 2];
+        assert!(parse(&v2(0x21, 0x11, &payload)).await.is_err());
+        // Length claims more than was sent.
+        let mut header = v2(0x21, 0x11, &payload);
+        header[15] = 0xff;
+        let io = Box::new(std::io::Cursor::new(header)) as BoxedIo;
+        assert!(read_header(io, Duration::from_secs(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_or_bad_signature() {
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: x\r\n").await.is_err());
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header[11] = b'X';
+        assert!(parse(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_without_header() {
+        let (_client, server) = tokio::io::duplex(64);
+        let io = Box::new(server) as BoxedIo;
+        assert!(read_header(io, Duration::from_millis(50)).await.is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use std::ops::Range;
+
+use hyper::body::Bytes;
+use hyper::header;
+
+use crate::conditional::Validators;
+use crate::types::HttpRequest;
+
+// More ranges than this are answered with the whole representation, so a request can't make
+// us seek all over a file for a handful of bytes each.
+const MAX_RANGES: usize = 16;
+
+pub(crate) enum Ranges {
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// Which bytes of a representation of `len` bytes the request asks for. A `Range` with a
+/// syntax error or one whose `If-Range` doesn't match is ignored, RFC 9110 14.2.
+pub(crate) fn requested(req: &HttpRequest, len: u64, validators: &Validators) -> Ranges {
+    let range = match req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) => v,
+        None => return Ranges::Full,
+    };
+    if let Some(if_range) = req.headers().get(header::IF_RANGE) {
+        match if_range.to_str() {
+            Ok(v) if validators.if_range_matches(v) => (),
+            _ => return Ranges::Full,
+        }
+    }
+    match parse(range, len) {
+        Some(ranges) if ranges.is_empty() => Ranges::Unsatisfiable,
+        Some(ranges) if ranges.len() > MAX_RANGES => Ranges::Full,
+        Some(ranges) => Ranges::Partial(ranges),
+        None => Ranges::Full,
+    }
+}
+
+// None on a syntax error, an empty Vec when no range overlaps the representation.
+fn parse(header: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-')?;
+        let range = if first.is_empty() {
+            // "-500" are the last 500 bytes.
+            let suffix = parse_position(last)?;
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            len.saturating_sub(suffix)..len
+        } else {
+            let first = parse_position(first)?;
+            let end = match last {
+                "" => len,
+                v => {
+                    let last = parse_position(v)?;
+                    if last < first {
+                        return None;
+                    }
+                    last.saturating_add(1).min(len)
+                }
+            };
+            if first >= len {
+                continue;
+            }
+            first..end
+        };
+        ranges.push(range);
+    }
+    Some(coalesce(ranges))
+}
+
+// Only digits, u64::from_str would also take a leading "+".
+fn parse_position(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// Overlapping ranges are merged, otherwise "0-,0-,0-" would send the whole representation
+// three times. Ranges that don't overlap keep the order they were asked for in.
+fn coalesce(ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let mut sorted = ranges.clone();
+    sorted.sort_by_key(|v| v.start);
+    if sorted.windows(2).all(|w| w[0].end <= w[1].start) {
+        return ranges;
+    }
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+pub(crate) fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+pub(crate) fn unsatisfied_range(len: u64) -> String {
+    format!("bytes */{}", len)
+}
+
+/// The framing of a `multipart/byteranges` body. Each part header is followed by the bytes of
+/// its range, the trailer closes the body.
+pub(crate) struct Multipart {
+    pub boundary: String,
+    pub parts: Vec<(Bytes, Range<u64>)>,
+    pub trailer: Bytes,
+}
+
+impl Multipart {
+    pub fn new(ranges: Vec<Range<u64>>, len: u64, content_type: &str) -> Self {
+        let boundary = uuid::Uuid::new_v4().simple().to_string();
+        let parts = ranges
+            .into_iter()
+            .map(|range| {
+                let header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    content_range(&range, len)
+                );
+                (Bytes::from(header), range)
+            })
+            .collect();
+        let trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+        Multipart {
+            boundary,
+            parts,
+            trailer,
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    pub fn content_length(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|(header, range)| header.len() as u64 + range.end - range.start)
+            .sum();
+        parts + self.trailer.len() as u64
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)] // a Vec of one range is what parse returns
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse("bytes=0-99", 1000), Some(vec![0..100]));
+        assert_eq!(parse("bytes=500-", 1000), Some(vec![500..1000]));
+        assert_eq!(parse("bytes=-300", 1000), Some(vec![700..1000]));
+        assert_eq!(parse("bytes=-3000", 1000), Some(vec![0..1000]));
+        assert_eq!(parse("bytes=900-2000", 1000), Some(vec![900..1000]));
+        assert_eq!(parse("bytes=999-999", 1000), Some(vec![999..1000]));
+        assert_eq!(parse("BYTES=0-0", 1000), Some(vec![0..1]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=1000-1100", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=1000-, -0", 1000), Some(vec![]));
+    }
+
+    #[test]
+    fn zero_length_representation() {
+        assert_eq!(parse("bytes=-1", 0), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 0), Some(vec![]));
+        assert_eq!(parse("bytes=0-", 0), Some(vec![]));
+        assert_eq!(parse("bytes=0-0", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            parse("bytes=500-599, 0-99, -100", 1000),
+            Some(vec![500..600, 0..100, 900..1000])
+        );
+        // The unsatisfiable one is dropped, the rest is served.
+        assert_eq!(parse("bytes=0-9, 2000-", 1000), Some(vec![0..10]));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(parse("bytes=0-, 0-, 0-", 1000), Some(vec![0..1000]));
+        assert_eq!(
+            parse("bytes=500-700, 0-99, 600-800, 50-60", 1000),
+            Some(vec![0..100, 500..801])
+        );
+        assert_eq!(parse("bytes=-500, 400-599", 1000), Some(vec![400..1000]));
+        // Adjacent ranges don't overlap.
+        assert_eq!(parse("bytes=10-19, 0-9", 1000), Some(vec![10..20, 0..10]));
+    }
+
+    #[test]
+    fn garbage_is_ignored() {
+        for header in [
+            "",
+            "bytes",
+            "bytes=",
+            "bytes=abc",
+            "bytes=5",
+            "bytes=10-5",
+            "bytes=-",
+            "bytes=0-1-2",
+            "bytes=+1-2",
+            "bytes=0-99,",
+            "items=0-99",
+            "bytes=18446744073709551616-",
+        ] {
+            assert_eq!(parse(header, 1000), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn content_range_header() {
+        assert_eq!(content_range(&(0..100), 1000), "bytes 0-99/1000");
+        assert_eq!(content_range(&(999..1000), 1000), "bytes 999-999/1000");
+        assert_eq!(unsatisfied_range(0), "bytes */0");
+    }
+
+    #[test]
+    fn multipart_length_matches_framing() {
+        let multipart = Multipart::new(vec![0..10, 20..25], 100, "text/plain");
+        let body_len = multipart.parts[0].0.len() + 10 + multipart.parts[1].0.len() + 5;
+        assert_eq!(
+            multipart.content_length(),
+            (body_len + multipart.trailer.len()) as u64
+        );
+        assert!(multipart.content_type().ends_with(&multipart.boundary));
+        let first = String::from_utf8_lossy(&multipart.parts[0].0).to_string();
+        assert!(first.contains("Content-Range: bytes 0-9/100"));
+    }
+}
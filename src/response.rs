@@ -1,22 +1,58 @@
-use crate::types::HttpResonse;
+use crate::types::{HttpBody, HttpResonse};
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
 use hyper::{header, Response, StatusCode};
-use http_body_util::Full;
-use hyper::body::Bytes;
 use serde_json::Value;
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
 
-pub fn build_response(
-    body_text: String,
+pub fn full_body<T: Into<Bytes>>(chunk: T) -> HttpBody {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+pub fn empty_body() -> HttpBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+/// A body sent chunk by chunk as the stream yields them. An error aborts the response.
+pub fn stream_body<S, E>(stream: S) -> HttpBody
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<anyhow::Error> + 'static,
+{
+    StreamBody::new(stream.map_ok(Frame::data).map_err(Into::into)).boxed_unsync()
+}
+
+/// A body read from `reader` as the client consumes it, e.g. a `tokio::fs::File`.
+pub fn reader_body<R>(reader: R) -> HttpBody
+where
+    R: AsyncRead + Send + 'static,
+{
+    stream_body(ReaderStream::new(reader))
+}
+
+pub fn build_stream_response(
+    body: HttpBody,
     status_code: StatusCode,
     content_type: &str,
 ) -> anyhow::Result<HttpResonse> {
-    let mut r = Response::builder()
-        .status(status_code)
-        .body(Full::new(Bytes::from(body_text)))?;
+    let mut r = Response::builder().status(status_code).body(body)?;
     r.headers_mut()
         .insert(header::CONTENT_TYPE, content_type.parse()?);
     Ok(r)
 }
 
+pub fn build_response(
+    body_text: String,
+    status_code: StatusCode,
+    content_type: &str,
+) -> anyhow::Result<HttpResonse> {
+    build_stream_response(full_body(body_text), status_code, content_type)
+}
+
 pub fn response_json(body_text: String, status_code: StatusCode) -> anyhow::Result<HttpResonse> {
     build_response(body_text, status_code, "application/json")
 }
@@ -29,14 +65,48 @@ pub fn ret_json(status_code: StatusCode, v: Value) -> anyhow::Result<HttpResonse
     response_json(v.to_string(), status_code)
 }
 
+/// An error that maps to a specific status code instead of a 500 when a view or middleware
+/// returns it.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// The response for an error returned by a view or middleware.
+pub fn error_response(error: anyhow::Error) -> HttpResonse {
+    match error.downcast_ref::<HttpError>() {
+        Some(e) => build_response(e.message.clone(), e.status, "text/plain")
+            .unwrap_or_else(internal_server_error_force),
+        None => internal_server_error_force(error),
+    }
+}
+
 pub fn internal_server_error(error: anyhow::Error) -> anyhow::Result<HttpResonse> {
     build_response(
-        format!("Error: {}", error.to_string()),
+        format!("Error: {}", error),
         StatusCode::INTERNAL_SERVER_ERROR,
         "text/html",
     )
 }
 
 pub fn internal_server_error_force(error: anyhow::Error) -> HttpResonse {
-    return internal_server_error(error).unwrap();
+    internal_server_error(error).unwrap()
 }
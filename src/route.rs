@@ -16,7 +16,7 @@ pub fn match_view(
 ) -> Option<(Arc<dyn View>, ViewPathArgs)> {
     for view in routes.iter() {
         let re = &view.re_path();
-        if let Some(caps) = re.captures(&path) {
+        if let Some(caps) = re.captures(path) {
             let view_args = caps_to_map(re, &caps);
             return Some((view.clone(), view_args));
         }
@@ -0,0 +1,660 @@
+use crate::connection::ConnectionInfo;
+use crate::listener::Listener;
+use crate::proxy_protocol;
+use crate::tls::{Tls, TlsInfo};
+use crate::SimpleApi;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio_rustls::TlsAcceptor;
+
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+// TCP, unix and TLS streams all end up behind the same type.
+pub(crate) type BoxedIo = Box<dyn Io>;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// How long to wait before accepting again when the process is out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// hyper refuses read buffers smaller than this.
+const MIN_MAX_HEADER_SIZE: usize = 8192;
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    keep_alive: bool,
+    header_read_timeout: Option<Duration>,
+    max_header_size: Option<usize>,
+    pipeline_flush: bool,
+    max_connections: Option<usize>,
+    tcp_nodelay: bool,
+    reuse_port: bool,
+    backlog: u32,
+    shutdown_timeout: Duration,
+    http2: bool,
+    http2_max_concurrent_streams: Option<u32>,
+    http2_initial_stream_window_size: Option<u32>,
+    http2_initial_connection_window_size: Option<u32>,
+    http2_adaptive_window: bool,
+    http2_keep_alive_interval: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            keep_alive: true,
+            header_read_timeout: Some(Duration::from_secs(30)),
+            max_header_size: None,
+            pipeline_flush: false,
+            max_connections: None,
+            tcp_nodelay: false,
+            reuse_port: false,
+            backlog: 1024,
+            shutdown_timeout: Duration::from_secs(30),
+            http2: true,
+            http2_max_concurrent_streams: None,
+            http2_initial_stream_window_size: None,
+            http2_initial_connection_window_size: None,
+            http2_adaptive_window: false,
+            http2_keep_alive_interval: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// HTTP/1 keep-alive, enabled by default.
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.keep_alive = enabled;
+        self
+    }
+
+    /// Closes connections that don't send a full request head in time. `None` disables it.
+    pub fn header_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// Upper bound of the connection read buffer, which caps the size of the request head.
+    /// Values below 8192 bytes are raised to 8192.
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.max_header_size = Some(size.max(MIN_MAX_HEADER_SIZE));
+        self
+    }
+
+    /// Aggregates flushes of pipelined responses.
+    pub fn pipeline_flush(mut self, enabled: bool) -> Self {
+        self.pipeline_flush = enabled;
+        self
+    }
+
+    /// Stops accepting new sockets while `max` connections are open.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Sets SO_REUSEPORT on the listening socket. Ignored on non-unix platforms.
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.reuse_port = enabled;
+        self
+    }
+
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// How long in-flight connections may take to finish once shutdown starts.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serve HTTP/2 next to HTTP/1.1 on the same socket, enabled by default. Plain-text
+    /// connections starting with the HTTP/2 preface are served as h2c (prior knowledge).
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.http2 = enabled;
+        self
+    }
+
+    pub fn http2_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.http2_max_concurrent_streams = Some(max);
+        self
+    }
+
+    pub fn http2_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_stream_window_size = Some(size);
+        self
+    }
+
+    pub fn http2_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.http2_initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Lets hyper grow the flow control windows based on measured bandwidth. When enabled, the
+    /// explicit window sizes are ignored.
+    pub fn http2_adaptive_window(mut self, enabled: bool) -> Self {
+        self.http2_adaptive_window = enabled;
+        self
+    }
+
+    /// Sends HTTP/2 pings at this interval to keep idle connections alive.
+    pub fn http2_keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.http2_keep_alive_interval = interval;
+        self
+    }
+
+    pub(crate) fn get_tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub(crate) fn get_reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+
+    pub(crate) fn get_backlog(&self) -> u32 {
+        self.backlog
+    }
+
+    fn conn_builder(&self) -> ConnBuilder {
+        if !self.http2 {
+            let mut http1 = http1::Builder::new();
+            http1
+                .timer(TokioTimer::new())
+                .keep_alive(self.keep_alive)
+                .pipeline_flush(self.pipeline_flush)
+                .header_read_timeout(self.header_read_timeout);
+            if let Some(size) = self.max_header_size {
+                http1.max_buf_size(size);
+            }
+            return ConnBuilder::Http1(http1);
+        }
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        {
+            let mut http1 = builder.http1();
+            http1
+                .timer(TokioTimer::new())
+                .keep_alive(self.keep_alive)
+                .pipeline_flush(self.pipeline_flush);
+            if let Some(timeout) = self.header_read_timeout {
+                http1.header_read_timeout(timeout);
+            }
+            if let Some(size) = self.max_header_size {
+                http1.max_buf_size(size);
+            }
+        }
+        {
+            let mut http2 = builder.http2();
+            http2
+                .timer(TokioTimer::new())
+                .adaptive_window(self.http2_adaptive_window)
+                .keep_alive_interval(self.http2_keep_alive_interval)
+                .max_concurrent_streams(self.http2_max_concurrent_streams);
+            if let Some(size) = self.http2_initial_stream_window_size {
+                http2.initial_stream_window_size(size);
+            }
+            if let Some(size) = self.http2_initial_connection_window_size {
+                http2.initial_connection_window_size(size);
+            }
+            if let Some(size) = self.max_header_size {
+                http2.max_header_list_size(size as u32);
+            }
+        }
+        ConnBuilder::Auto(builder)
+    }
+}
+
+// The auto builder ignores http1_only() once upgrades are enabled and would still answer the
+// HTTP/2 preface, so HTTP/1-only connections go through hyper's own builder.
+enum ConnBuilder {
+    Auto(auto::Builder<TokioExecutor>),
+    Http1(http1::Builder),
+}
+
+/// Resolves once the process receives SIGINT (Ctrl-C), or SIGTERM on unix.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
+// Accepts connections on all listeners until `signal` resolves, then stops accepting and asks
+// every in-flight connection to finish. Connections still alive after `shutdown_timeout` are
+// dropped.
+pub(crate) async fn serve<F>(
+    app: Arc<SimpleApi>,
+    listeners: Vec<Listener>,
+    signal: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    if listeners.is_empty() {
+        return Err(anyhow::anyhow!("No listener to serve on"));
+    }
+    let config = app.server_config().clone();
+    let limit = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+    let mut handshakes = Vec::with_capacity(listeners.len());
+    let mut reloaders = Vec::new();
+    for listener in listeners.iter() {
+        let tls = match listener.tls_config() {
+            Some(v) => {
+                let t = Tls::new(v, config.http2)?;
+                reloaders.push(t.spawn_reloader(v));
+                Some((t.acceptor, v.get_handshake_timeout()))
+            }
+            None => None,
+        };
+        handshakes.push(Handshake {
+            proxy_protocol: listener.proxy_protocol_timeout(),
+            tls,
+        });
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (abort_tx, abort_rx) = watch::channel(false);
+    // Every connection task holds a sender, recv() returns None once all of them are gone.
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let mut next = 0;
+    tokio::pin!(signal);
+
+    loop {
+        // Waiting for a free slot before accepting leaves excess clients in the kernel backlog.
+        let permit = match limit {
+            Some(ref sem) => tokio::select! {
+                permit = sem.clone().acquire_owned() => Some(permit?),
+                _ = &mut signal => break,
+            },
+            None => None,
+        };
+        let accepted = tokio::select! {
+            res = accept_any(&listeners, &mut next) => res,
+            _ = &mut signal => break,
+        };
+        // Accept errors are about the one connection or a passing shortage, only the signal
+        // ends the loop.
+        let (stream, mut info, index) = match accepted {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Error accepting connection: {:?}", err);
+                if is_out_of_fds(&err) {
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => (),
+                        _ = &mut signal => break,
+                    }
+                }
+                continue;
+            }
+        };
+        info.id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let app = app.clone();
+        let builder = config.conn_builder();
+        let handshake = handshakes[index].clone();
+        let drain = Drain {
+            shutdown: shutdown_rx.clone(),
+            abort: abort_rx.clone(),
+            _done: done_tx.clone(),
+        };
+        tokio::task::spawn(async move {
+            let accepted = tokio::select! {
+                res = accept_connection(stream, info, handshake) => res,
+                _ = drain.shutdown() => return,
+            };
+            match accepted {
+                Ok((io, info)) => serve_connection(app, builder, io, info, drain).await,
+                Err(err) => println!("Error accepting connection: {:?}", err),
+            }
+            drop(permit);
+        });
+    }
+
+    drop(listeners);
+    for reloader in reloaders {
+        reloader.abort();
+    }
+    let _ = shutdown_tx.send(true);
+    drop(done_tx);
+    if tokio::time::timeout(config.shutdown_timeout, done_rx.recv())
+        .await
+        .is_err()
+    {
+        println!("Shutdown timeout reached, aborting remaining connections");
+        let _ = abort_tx.send(true);
+        done_rx.recv().await;
+    }
+    Ok(())
+}
+
+// EMFILE and ENFILE: accepting again right away would fail the same way until connections
+// close and free descriptors.
+fn is_out_of_fds(err: &std::io::Error) -> bool {
+    cfg!(unix) && matches!(err.raw_os_error(), Some(23 | 24))
+}
+
+// Resolves with the first connection any listener has ready. Polling starts at a different
+// listener each time so a busy one can't starve the others.
+async fn accept_any(
+    listeners: &[Listener],
+    next: &mut usize,
+) -> std::io::Result<(BoxedIo, ConnectionInfo, usize)> {
+    std::future::poll_fn(|cx| {
+        for i in 0..listeners.len() {
+            let index = (*next + i) % listeners.len();
+            if let Poll::Ready(res) = listeners[index].poll_accept(cx) {
+                *next = index + 1;
+                return Poll::Ready(res.map(|(io, info)| (io, info, index)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+// What a listener's connections go through before HTTP is spoken on them.
+#[derive(Clone)]
+struct Handshake {
+    proxy_protocol: Option<Duration>,
+    tls: Option<(TlsAcceptor, Duration)>,
+}
+
+// Runs the per-connection handshakes and collects what they negotiated. The PROXY header is
+// sent by the load balancer in front of the TLS handshake.
+async fn accept_connection(
+    stream: BoxedIo,
+    mut info: ConnectionInfo,
+    handshake: Handshake,
+) -> anyhow::Result<(BoxedIo, ConnectionInfo)> {
+    let stream = match handshake.proxy_protocol {
+        Some(timeout) => {
+            let (stream, proxy) = proxy_protocol::read_header(stream, timeout).await?;
+            info.proxy = Some(proxy);
+            stream
+        }
+        None => stream,
+    };
+    let io: BoxedIo = match handshake.tls {
+        Some((acceptor, timeout)) => {
+            let stream = tokio::time::timeout(timeout, acceptor.accept(stream)).await??;
+            info.tls = Some(TlsInfo::from_connection(stream.get_ref().1));
+            Box::new(stream)
+        }
+        None => Box::new(stream),
+    };
+    Ok((io, info))
+}
+
+/// Held by everything serving a connection, including upgraded connections that outlive their
+/// request. Shutdown waits until every copy is dropped or the timeout runs out.
+#[derive(Clone)]
+pub(crate) struct Drain {
+    shutdown: watch::Receiver<bool>,
+    abort: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl Drain {
+    /// Resolves once graceful shutdown begins.
+    pub async fn shutdown(&self) {
+        wait_for_true(self.shutdown.clone()).await
+    }
+
+    /// Resolves once the shutdown timeout has run out.
+    pub async fn abort(&self) {
+        wait_for_true(self.abort.clone()).await
+    }
+}
+
+async fn serve_connection(
+    app: Arc<SimpleApi>,
+    builder: ConnBuilder,
+    io: BoxedIo,
+    info: ConnectionInfo,
+    drain: Drain,
+) {
+    let service = app.connection_service(info).drain(drain.clone());
+    let io = TokioIo::new(io);
+    match builder {
+        ConnBuilder::Auto(builder) => {
+            let conn = builder.serve_connection_with_upgrades(io, service);
+            drive(conn, |c| c.graceful_shutdown(), &drain).await
+        }
+        ConnBuilder::Http1(builder) => {
+            let conn = builder.serve_connection(io, service).with_upgrades();
+            drive(conn, |c| c.graceful_shutdown(), &drain).await
+        }
+    }
+}
+
+// Runs the connection to completion, or asks it to finish its current requests once shutdown
+// begins and drops it when the timeout runs out.
+async fn drive<C, E, G>(conn: C, graceful_shutdown: G, drain: &Drain)
+where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Debug,
+    G: FnOnce(Pin<&mut C>),
+{
+    tokio::pin!(conn);
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = drain.shutdown() => {
+            graceful_shutdown(conn.as_mut());
+            tokio::select! {
+                res = conn.as_mut() => res,
+                _ = drain.abort() => Ok(()),
+            }
+        }
+    };
+    if let Err(err) = res {
+        println!("Error serving connection: {:?}", err);
+    }
+}
+
+async fn wait_for_true(mut rx: watch::Receiver<bool>) {
+    // An Err means the sender is gone, which only happens when the server is going away anyway.
+    let _ = rx.wait_for(|v| *v).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::response;
+    use crate::test_support::{read_head, request, TestServer};
+    use crate::types::{HttpRequest, HttpResonse};
+    use crate::view::View;
+    use async_trait::async_trait;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::Method;
+    use regex::Regex;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::Notify;
+
+    // Answers GET /slow after `delay`, GET /fast right away.
+    struct Slow {
+        delay: Duration,
+        started: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl View for Slow {
+        async fn call(
+            &self,
+            req: &mut HttpRequest,
+            _ctx: &mut Context,
+        ) -> anyhow::Result<HttpResonse> {
+            if req.uri().path() == "/slow" {
+                self.started.notify_one();
+                tokio::time::sleep(self.delay).await;
+            }
+            response::ok_json(serde_json::json!("done"))
+        }
+        fn methods(&self) -> Vec<Method> {
+            vec![Method::GET]
+        }
+        fn re_path(&self) -> Regex {
+            Regex::new("^/(slow|fast)$").unwrap()
+        }
+    }
+
+    async fn serve(delay: Duration, config: ServerConfig) -> (TestServer, Arc<Notify>) {
+        let started = Arc::new(Notify::new());
+        let mut app = SimpleApi::new();
+        app.set_server_config(config);
+        app.add_route(Slow {
+            delay,
+            started: started.clone(),
+        });
+        (TestServer::start(app).await, started)
+    }
+
+    #[tokio::test]
+    async fn finishes_in_flight_requests_on_shutdown() {
+        let config = ServerConfig::new().shutdown_timeout(Duration::from_secs(10));
+        let (mut server, started) = serve(Duration::from_millis(300), config).await;
+        let addr = server.addr;
+        let slow = tokio::spawn(async move { request(addr, "GET /slow HTTP/1.1", b"").await });
+        started.notified().await;
+
+        server.shutdown();
+        let res = slow.await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.text(), "\"done\"");
+        tokio::time::timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cuts_off_requests_at_the_shutdown_timeout() {
+        let config = ServerConfig::new().shutdown_timeout(Duration::from_millis(200));
+        let (mut server, started) = serve(Duration::from_secs(60), config).await;
+        let mut stream = TcpStream::connect(server.addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        started.notified().await;
+
+        let begin = Instant::now();
+        server.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(begin.elapsed() >= Duration::from_millis(200));
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .unwrap();
+        assert!(read.is_err() || rest.is_empty(), "{:?}", rest);
+    }
+
+    #[tokio::test]
+    async fn run_with_shutdown_returns_on_signal() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let run = tokio::spawn(SimpleApi::new().run_with_shutdown("127.0.0.1:0", async {
+            let _ = rx.await;
+        }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!run.is_finished());
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_free_connection_slot() {
+        let config = ServerConfig::new().max_connections(1);
+        let (server, _) = serve(Duration::ZERO, config).await;
+        let fast = b"GET /fast HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let mut first = TcpStream::connect(server.addr).await.unwrap();
+        first.write_all(fast).await.unwrap();
+        assert_eq!(read_head(&mut first).await.status, 200);
+
+        // The kernel completes the connect, but nothing reads from the socket yet.
+        let mut second = TcpStream::connect(server.addr).await.unwrap();
+        second.write_all(fast).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(300), read_head(&mut second));
+        assert!(waiting.await.is_err());
+
+        drop(first);
+        let res = tokio::time::timeout(Duration::from_secs(5), read_head(&mut second))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+    }
+
+    // Speaks HTTP/2 with prior knowledge over plain TCP, as h2c clients do.
+    async fn h2c_get(addr: std::net::SocketAddr) -> anyhow::Result<hyper::Response<Incoming>> {
+        let tcp = TcpStream::connect(addr).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tcp)).await?;
+        tokio::spawn(conn);
+        let req =
+            hyper::Request::get(format!("http://{}/fast", addr)).body(Empty::<Bytes>::new())?;
+        Ok(sender.send_request(req).await?)
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_with_prior_knowledge() {
+        let (server, _) = serve(Duration::ZERO, ServerConfig::new()).await;
+        let res = h2c_get(server.addr).await.unwrap();
+        assert_eq!(res.version(), hyper::Version::HTTP_2);
+        assert_eq!(res.status(), 200);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"\"done\"");
+
+        let (server, _) = serve(Duration::ZERO, ServerConfig::new().http2(false)).await;
+        assert!(h2c_get(server.addr).await.is_err());
+        let res = request(server.addr, "GET /fast HTTP/1.1", b"").await;
+        assert_eq!(res.status, 200);
+    }
+}
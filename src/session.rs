@@ -27,10 +27,8 @@ fn build_session_key(session_id: &str) -> String {
     build_rkey(&vec![SESSION_PREFIX, session_id])
 }
 
-static NO_SUCH_USER: &'static str = "Can't find this session";
-static NO_COOKIE: &'static str = "No cookie";
-static NO_SESSION_ID: &'static str = "No session id";
-static SESSION_PREFIX: &'static str = "session";
+static NO_SUCH_USER: &str = "Can't find this session";
+static SESSION_PREFIX: &str = "session";
 
 #[async_trait]
 impl Session for RedisSession {
@@ -39,7 +37,8 @@ impl Session for RedisSession {
     }
 
     fn set(&mut self, key: &str, value: Value) -> anyhow::Result<()> {
-        Ok(self.inner[key] = value)
+        self.inner[key] = value;
+        Ok(())
     }
 
     fn value(&self) -> &Value {
@@ -59,6 +58,7 @@ pub trait Session: Send + Sync + 'static + any::Any {
 }
 
 #[async_trait]
+#[allow(clippy::borrowed_box)]
 pub trait SessionProvider: Send + Sync + 'static {
     // flask里SecureCookieSessionInterface是用的策略是：cookie里找不到session内容，或者session内容解码失败，都创建一个新的session
     // 这里也都参考这个来实现吧
@@ -103,7 +103,7 @@ impl SessionProvider for RedisSessionProvider {
             None => return Ok(Some(self.new_session())),
         };
         let mut conn = self.redis_cli.get_async_connection().await?;
-        let ov: Option<String> = conn.get(build_session_key(&sid)).await?;
+        let ov: Option<String> = conn.get(build_session_key(sid)).await?;
         let serialized = ov.ok_or(anyhow::anyhow!(NO_SUCH_USER))?;
         Ok(Some(Box::new(RedisSession {
             inner: serde_json::from_str(serialized.as_str())?,
@@ -124,7 +124,8 @@ impl SessionProvider for RedisSessionProvider {
         let value = session.value();
         let mut conn = self.redis_cli.get_async_connection().await?;
         let serialized = serde_json::to_string(value)?;
-        conn.set(build_session_key(&sid), serialized).await?;
+        conn.set::<_, _, ()>(build_session_key(sid), serialized)
+            .await?;
 
         let cookie = cookie::Cookie::new("session_id", sid.to_string());
         res.headers_mut().append(
@@ -142,6 +143,12 @@ pub struct CookieSession {
     inner: Value,
 }
 
+impl Default for CookieSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CookieSession {
     pub fn new() -> Self {
         CookieSession { inner: json!({}) }
@@ -158,7 +165,8 @@ impl Session for CookieSession {
     }
 
     fn set(&mut self, key: &str, value: Value) -> anyhow::Result<()> {
-        Ok(self.inner[key] = value)
+        self.inner[key] = value;
+        Ok(())
     }
 
     fn value(&self) -> &Value {
@@ -184,7 +192,7 @@ impl CookieSessionProvider {
     }
 
     pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
-        Ok(CookieSessionProvider::from_slice(&hex::decode(hex)?)?)
+        CookieSessionProvider::from_slice(&hex::decode(hex)?)
     }
 }
 
@@ -214,7 +222,7 @@ impl SessionProvider for CookieSessionProvider {
             .split(self.separator())
             .collect::<Vec<&str>>();
         let (session, signature) = match v.as_slice() {
-            [session_b6, signature_b6] => (b6_to_s(session_b6)?, b6_to_vu8(&signature_b6)?),
+            [session_b6, signature_b6] => (b6_to_s(session_b6)?, b6_to_vu8(signature_b6)?),
             _ => return Err(anyhow::anyhow!("Signed session format is invalid")),
         };
 
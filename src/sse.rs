@@ -0,0 +1,315 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Stream;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame};
+use hyper::{header, Request, Response, StatusCode};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::types::HttpResonse;
+
+/// One server-sent event. Multi-line data is sent as several `data:` lines and joined back
+/// together by the browser.
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    event: Option<String>,
+    data: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+// Field values end at a line break, so they can't contain one.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], "")
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn data(mut self, data: impl Into<String>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn json_data(self, data: &serde_json::Value) -> Self {
+        self.data(data.to_string())
+    }
+
+    /// Event name, dispatched to `addEventListener(name, ...)` instead of `onmessage`.
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Sent back by the browser as `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// How long the browser waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(single_line(comment));
+        self
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut s = String::new();
+        if let Some(ref comment) = self.comment {
+            s.push_str(&format!(": {}\n", comment));
+        }
+        if let Some(ref event) = self.event {
+            s.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(ref id) = self.id {
+            s.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            s.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        // CRLF, a lone CR and LF all end a line in the browser, a CR left in the data would
+        // let it start a field of its own.
+        if let Some(ref data) = self.data {
+            for line in data.split("\r\n").flat_map(|v| v.split(['\r', '\n'])) {
+                s.push_str(&format!("data: {}\n", line));
+            }
+        }
+        s.push('\n');
+        Bytes::from(s)
+    }
+}
+
+/// The `Last-Event-ID` a reconnecting client sent, to resume the stream after it.
+pub fn last_event_id<B>(req: &Request<B>) -> Option<String> {
+    req.headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// A `text/event-stream` response fed by a stream of events. The stream is dropped once the
+/// client disconnects, which is how the producer learns about it, see [`channel`].
+pub struct Sse {
+    stream: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<Duration>,
+}
+
+impl Sse {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        Sse {
+            stream: Box::pin(stream),
+            keep_alive: Some(Duration::from_secs(15)),
+        }
+    }
+
+    /// Sends a comment line when no event went out for this long, 15 seconds by default.
+    /// Besides keeping proxies from closing the connection, this is what detects a client
+    /// that went away while no events were being sent.
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    pub fn into_response(self) -> anyhow::Result<HttpResonse> {
+        let keep_alive = self.keep_alive.map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let body = SseBody {
+            stream: self.stream,
+            keep_alive,
+        };
+        let mut r = Response::builder()
+            .status(StatusCode::OK)
+            .body(body.boxed_unsync())?;
+        let headers = r.headers_mut();
+        headers.insert(header::CONTENT_TYPE, "text/event-stream".parse()?);
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse()?);
+        // Keeps nginx from buffering the stream.
+        headers.insert("x-accel-buffering", "no".parse()?);
+        Ok(r)
+    }
+}
+
+/// Sends events into an [`Sse`] response from another task.
+#[derive(Clone)]
+pub struct SseSender {
+    tx: mpsc::Sender<Event>,
+}
+
+impl SseSender {
+    /// Fails once the client has disconnected.
+    pub async fn send(&self, event: Event) -> anyhow::Result<()> {
+        self.tx
+            .send(event)
+            .await
+            .map_err(|_| anyhow::anyhow!("SSE client disconnected"))
+    }
+
+    /// Resolves once the client has disconnected.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// An [`Sse`] response with a sender for it. `buffer` events can be queued before `send`
+/// waits for the client to catch up.
+pub fn channel(buffer: usize) -> (SseSender, Sse) {
+    let (tx, mut rx) = mpsc::channel(buffer);
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    (SseSender { tx }, Sse::new(stream))
+}
+
+struct SseBody {
+    stream: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<Interval>,
+}
+
+impl Body for SseBody {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        match this.stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                if let Some(ref mut interval) = this.keep_alive {
+                    interval.reset();
+                }
+                return Poll::Ready(Some(Ok(Frame::data(event.encode()))));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => (),
+        }
+        if let Some(ref mut interval) = this.keep_alive {
+            if interval.poll_tick(cx).is_ready() {
+                return Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b":\n\n")))));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(event: Event) -> String {
+        String::from_utf8(event.encode().to_vec()).unwrap()
+    }
+
+    async fn next_chunk(res: &mut HttpResonse) -> String {
+        let frame = tokio::time::timeout(Duration::from_secs(5), res.body_mut().frame())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn splits_data_on_every_line_break() {
+        let event = Event::new().data("a\nb\r\nc\rd\n");
+        assert_eq!(
+            encoded(event),
+            "data: a\ndata: b\ndata: c\ndata: d\ndata: \n\n"
+        );
+        assert_eq!(encoded(Event::new().data("")), "data: \n\n");
+    }
+
+    #[test]
+    fn data_cannot_inject_fields() {
+        let text = encoded(Event::new().data("x\rid: 999\revent: admin\r\rdata: y"));
+        assert_eq!(
+            text,
+            "data: x\ndata: id: 999\ndata: event: admin\ndata: \ndata: data: y\n\n"
+        );
+        // Every line a browser sees, split on CR as well as LF, is a data line.
+        let lines: Vec<&str> = text.split(['\r', '\n']).filter(|v| !v.is_empty()).collect();
+        assert!(lines.iter().all(|v| v.starts_with("data: ")), "{:?}", lines);
+    }
+
+    #[test]
+    fn writes_fields() {
+        let event = Event::new()
+            .comment("hi")
+            .event("tick\r\n")
+            .id("7\nretry: 0")
+            .retry(Duration::from_millis(2500))
+            .json_data(&serde_json::json!({"n": 1}));
+        assert_eq!(
+            encoded(event),
+            ": hi\nevent: tick\nid: 7retry: 0\nretry: 2500\ndata: {\"n\":1}\n\n"
+        );
+        assert_eq!(encoded(Event::new().id("")), "id: \n\n");
+    }
+
+    #[test]
+    fn reads_last_event_id() {
+        let req = Request::get("/events")
+            .header("last-event-id", "41")
+            .body(())
+            .unwrap();
+        assert_eq!(last_event_id(&req).as_deref(), Some("41"));
+        let req = Request::get("/events").body(()).unwrap();
+        assert_eq!(last_event_id(&req), None);
+    }
+
+    #[tokio::test]
+    async fn streams_events_with_headers() {
+        let (tx, sse) = channel(4);
+        let mut res = sse.into_response().unwrap();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+        tx.send(Event::new().id("1").data("one")).await.unwrap();
+        assert_eq!(next_chunk(&mut res).await, "id: 1\ndata: one\n\n");
+    }
+
+    #[tokio::test]
+    async fn sends_keep_alive_when_idle() {
+        let (tx, sse) = channel(4);
+        let mut res = sse
+            .keep_alive(Some(Duration::from_millis(50)))
+            .into_response()
+            .unwrap();
+        assert_eq!(next_chunk(&mut res).await, ":\n\n");
+        tx.send(Event::new().data("x")).await.unwrap();
+        assert_eq!(next_chunk(&mut res).await, "data: x\n\n");
+        assert_eq!(next_chunk(&mut res).await, ":\n\n");
+    }
+
+    #[tokio::test]
+    async fn sender_sees_the_client_go_away() {
+        let (tx, sse) = channel(1);
+        let res = sse.into_response().unwrap();
+        assert!(!tx.is_closed());
+        drop(res);
+        tokio::time::timeout(Duration::from_secs(5), tx.closed())
+            .await
+            .unwrap();
+        assert!(tx.is_closed());
+        assert!(tx.send(Event::new().data("late")).await.is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Shared by the tests: an app served on a free local port and a bare-bones HTTP/1.1 client
+//! that writes requests byte for byte, so malformed ones can be sent too.
+
+use crate::listener::Listener;
+use crate::SimpleApi;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// An app running on 127.0.0.1 until [`TestServer::shutdown`] is called. Dropping it leaves
+/// the server running until the test's runtime goes away.
+pub(crate) struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    stopped: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestServer {
+    pub async fn start(app: SimpleApi) -> Self {
+        Self::start_with(app, |v| v).await
+    }
+
+    /// Like [`TestServer::start`], `listener` can change the listener first, e.g. add TLS.
+    pub async fn start_with(app: SimpleApi, listener: impl FnOnce(Listener) -> Listener) -> Self {
+        let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listeners = vec![listener(Listener::from(tcp))];
+        let (tx, rx) = oneshot::channel::<()>();
+        let signal = async {
+            if rx.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        let stopped = tokio::spawn(app.run_listeners_with_shutdown(listeners, signal));
+        TestServer {
+            addr,
+            shutdown: Some(tx),
+            stopped,
+        }
+    }
+
+    /// Sends the shutdown signal, connections are drained from then on.
+    pub fn shutdown(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Waits for the server to return from `run_listeners_with_shutdown`.
+    pub async fn stopped(self) -> anyhow::Result<()> {
+        self.stopped.await?
+    }
+}
+
+/// A response as read off the wire, with a chunked body already decoded.
+pub(crate) struct RawResponse {
+    pub status: u16,
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl RawResponse {
+    /// The first header named `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.eq_ignore_ascii_case(name).then(|| v.trim())
+        })
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    /// The body as JSON, `Null` when it isn't.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+}
+
+/// Sends `head`, the request line and any headers without the blank line, followed by `Host`,
+/// `Connection: close` and `Content-Length` headers and `body`, and reads the whole response.
+pub(crate) async fn request(addr: SocketAddr, head: impl AsRef<[u8]>, body: &[u8]) -> RawResponse {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    request_on(&mut stream, head, body).await
+}
+
+/// [`request`] over an open stream, e.g. a TLS one.
+pub(crate) async fn request_on<S>(
+    stream: &mut S,
+    head: impl AsRef<[u8]>,
+    body: &[u8],
+) -> RawResponse
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = head.as_ref().to_vec();
+    request.extend_from_slice(
+        format!(
+            "\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .as_bytes(),
+    );
+    request.extend_from_slice(body);
+    stream.write_all(&request).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    parse(&response)
+}
+
+/// Reads a response head and nothing after it, for connections that stay open.
+pub(crate) async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> RawResponse {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    parse(&head)
+}
+
+fn parse(response: &[u8]) -> RawResponse {
+    let split = response
+        .windows(4)
+        .position(|v| v == b"\r\n\r\n")
+        .map(|v| v + 4)
+        .unwrap_or(response.len());
+    let head = String::from_utf8_lossy(&response[..split])
+        .trim_end()
+        .to_string();
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| panic!("not an HTTP response: {:?}", head));
+    let mut res = RawResponse {
+        status,
+        head,
+        body: response[split..].to_vec(),
+    };
+    if res
+        .header("transfer-encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        res.body = dechunk(&res.body);
+    }
+    res
+}
+
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(end) = data.windows(2).position(|v| v == b"\r\n") {
+        let size = std::str::from_utf8(&data[..end]).unwrap();
+        let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        let start = end + 2;
+        body.extend_from_slice(&data[start..start + size]);
+        data = &data[start + size + 2..];
+    }
+    body
+}
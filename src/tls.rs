@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::{
+    self,
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert, ServerConnection,
+    },
+    sign::CertifiedKey,
+    Certificate, PrivateKey, RootCertStore,
+};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    client_auth_required: bool,
+    reload_interval: Option<Duration>,
+    reload_on_sighup: bool,
+    handshake_timeout: Duration,
+}
+
+impl TlsConfig {
+    /// `cert_path` is a PEM certificate chain, leaf first. `key_path` is a PEM PKCS#8, RSA or
+    /// SEC1 private key.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            client_auth_required: true,
+            reload_interval: None,
+            reload_on_sighup: true,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Enables mTLS, client certificates are verified against the PEM roots in `ca_path`.
+    /// With `required` set to false, clients without a certificate are still accepted.
+    pub fn client_ca(mut self, ca_path: impl Into<PathBuf>, required: bool) -> Self {
+        self.client_ca_path = Some(ca_path.into());
+        self.client_auth_required = required;
+        self
+    }
+
+    /// Re-reads the certificate and key from disk at this interval.
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = Some(interval);
+        self
+    }
+
+    /// Re-reads the certificate and key from disk on SIGHUP, enabled by default. Unix only.
+    pub fn reload_on_sighup(mut self, enabled: bool) -> Self {
+        self.reload_on_sighup = enabled;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn get_handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+}
+
+/// What was negotiated during the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+    pub server_name: Option<String>, // SNI sent by the client
+    pub alpn_protocol: Option<Vec<u8>>,
+    pub protocol_version: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub peer_certificates: Option<Vec<Vec<u8>>>, // DER, only with mTLS
+}
+
+impl TlsInfo {
+    pub(crate) fn from_connection(conn: &ServerConnection) -> Self {
+        TlsInfo {
+            server_name: conn.server_name().map(|v| v.to_string()),
+            alpn_protocol: conn.alpn_protocol().map(|v| v.to_vec()),
+            protocol_version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .map(|v| format!("{:?}", v.suite())),
+            peer_certificates: conn
+                .peer_certificates()
+                .map(|certs| certs.iter().map(|c| c.0.clone()).collect()),
+        }
+    }
+}
+
+// Hands out whatever certificate was loaded last, so it can be swapped without a restart.
+struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|v| v.clone())
+    }
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(v)
+            | rustls_pemfile::Item::RSAKey(v)
+            | rustls_pemfile::Item::ECKey(v) => return Ok(PrivateKey(v)),
+            _ => continue,
+        }
+    }
+    Err(anyhow!("No private key found in {}", path.display()))
+}
+
+fn load_certified_key(config: &TlsConfig) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = rustls::sign::any_supported_type(&load_key(&config.key_path)?)?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+pub(crate) struct Tls {
+    pub acceptor: TlsAcceptor,
+    resolver: Arc<CertResolver>,
+}
+
+impl Tls {
+    pub fn new(config: &TlsConfig, http2: bool) -> anyhow::Result<Self> {
+        let resolver = Arc::new(CertResolver {
+            current: RwLock::new(load_certified_key(config)?),
+        });
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match config.client_ca_path {
+            Some(ref ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_path)? {
+                    roots.add(&cert)?;
+                }
+                if config.client_auth_required {
+                    builder
+                        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                } else {
+                    builder.with_client_cert_verifier(
+                        AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                    )
+                }
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(resolver.clone());
+        if http2 {
+            server_config.alpn_protocols.push(b"h2".to_vec());
+        }
+        server_config.alpn_protocols.push(b"http/1.1".to_vec());
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            resolver,
+        })
+    }
+
+    // Runs until aborted. A failed reload is logged and the previous certificate stays in use.
+    pub fn spawn_reloader(&self, config: &TlsConfig) -> JoinHandle<()> {
+        let resolver = self.resolver.clone();
+        let config = config.clone();
+        tokio::task::spawn(async move {
+            #[cfg(unix)]
+            let mut hup = match config.reload_on_sighup {
+                true => tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok(),
+                false => None,
+            };
+            let mut interval = config.reload_interval.map(|v| {
+                let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + v, v);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+
+            loop {
+                let sighup = async {
+                    #[cfg(unix)]
+                    if let Some(ref mut hup) = hup {
+                        hup.recv().await;
+                        return;
+                    }
+                    std::future::pending::<()>().await
+                };
+                let tick = async {
+                    match interval {
+                        Some(ref mut interval) => {
+                            interval.tick().await;
+                        }
+                        None => std::future::pending::<()>().await,
+                    }
+                };
+                tokio::select! {
+                    _ = sighup => (),
+                    _ = tick => (),
+                }
+
+                match load_certified_key(&config) {
+                    Ok(key) => {
+                        if let Ok(mut current) = resolver.current.write() {
+                            *current = key;
+                        }
+                    }
+                    Err(e) => println!("Failed to reload TLS certificate: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request_on, TestServer};
+    use crate::SimpleApi;
+    use tokio::io::AsyncReadExt;
+    use tokio_rustls::TlsConnector;
+
+    // Writes a fresh self-signed certificate for localhost, returns its DER.
+    fn write_cert(config: &TlsConfig) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&config.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key_path, cert.serialize_private_key_pem()).unwrap();
+        // Every serialize call signs again, so take the DER from what was written.
+        load_certs(&config.cert_path).unwrap().remove(0).0
+    }
+
+    fn connector(trusted: &[&Vec<u8>]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for der in trusted {
+            roots.add(&Certificate(der.to_vec())).unwrap();
+        }
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsConnector::from(Arc::new(config))
+    }
+
+    // The DER of the certificate the acceptor presents in a handshake over an in-memory pipe.
+    async fn served_cert(tls: &Tls, connector: &TlsConnector) -> Vec<u8> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = tls.acceptor.clone();
+        let server = tokio::spawn(async move { acceptor.accept(server).await.map(|_| ()) });
+        let domain = rustls::ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(domain, client).await.unwrap();
+        server.await.unwrap().unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    fn config(dir: &tempfile::TempDir) -> TlsConfig {
+        TlsConfig::new(dir.path().join("cert.pem"), dir.path().join("key.pem"))
+            .reload_on_sighup(false)
+    }
+
+    async fn serve(config: TlsConfig) -> TestServer {
+        TestServer::start_with(SimpleApi::new(), |v| v.tls(config)).await
+    }
+
+    #[tokio::test]
+    async fn serves_http_over_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let der = write_cert(&config);
+        let addr = serve(config).await.addr;
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let domain = rustls::ServerName::try_from("localhost").unwrap();
+        let mut stream = connector(&[&der]).connect(domain, tcp).await.unwrap();
+        assert_eq!(
+            stream.get_ref().1.alpn_protocol(),
+            Some(b"http/1.1".as_slice())
+        );
+        let res = request_on(&mut stream, "GET /missing HTTP/1.1", b"").await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        write_cert(&config);
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+        let addr = serve(config).await.addr;
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let domain = rustls::ServerName::try_from("localhost").unwrap();
+        assert!(connector(&[&other]).connect(domain, tcp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_never_finish_the_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir).handshake_timeout(Duration::from_millis(100));
+        write_cert(&config);
+        let addr = serve(config).await.addr;
+
+        let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(Duration::from_secs(5), tcp.read(&mut buf))
+            .await
+            .expect("connection still open after the handshake timeout");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn reloads_certificate_on_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir).reload_interval(Duration::from_millis(50));
+        let first = write_cert(&config);
+        let tls = Tls::new(&config, false).unwrap();
+        let reloader = tls.spawn_reloader(&config);
+
+        let second = write_cert(&config);
+        let connector = connector(&[&first, &second]);
+        let mut served = served_cert(&tls, &connector).await;
+        for _ in 0..100 {
+            if served == second {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            served = served_cert(&tls, &connector).await;
+        }
+        assert_eq!(served, second);
+
+        // A broken file on disk keeps the last good certificate in use.
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(served_cert(&tls, &connector).await, second);
+        reloader.abort();
+    }
+
+    #[test]
+    fn fails_without_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        write_cert(&config);
+        std::fs::write(&config.key_path, "").unwrap();
+        assert!(Tls::new(&config, false).is_err());
+    }
+}
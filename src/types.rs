@@ -1,10 +1,11 @@
-use http_body_util::Full;
+use http_body_util::combinators::UnsyncBoxBody;
 use hyper::body::Bytes;
 use hyper::{body, Request, Response};
 use std::collections::HashMap;
 use std::{any::Any, sync::Arc};
 
-pub type HttpResonse = Response<Full<Bytes>>;
+pub type HttpBody = UnsyncBoxBody<Bytes, anyhow::Error>; // Buffered or streamed, see response::full_body
+pub type HttpResonse = Response<HttpBody>;
 pub type HttpRequest = Request<body::Incoming>;
 
 pub type State = Arc<dyn Any + Send + Sync>; // It stores globals, such as database connection pool, etc.
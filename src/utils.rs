@@ -5,7 +5,7 @@ pub mod cookie {
 
     pub fn parse_cookie(cookies_string: &str) -> CookieMap {
         let mut map = HashMap::new();
-        for cookie in Cookie::split_parse(cookies_string).into_iter() {
+        for cookie in Cookie::split_parse(cookies_string) {
             let cookie = match cookie {
                 Ok(v) => v,
                 Err(_) => continue,
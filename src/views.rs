@@ -1,26 +1,360 @@
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
 use anyhow::anyhow;
 use async_trait::async_trait;
-use http_body_util::Full;
+use futures_util::{stream, StreamExt, TryStreamExt};
 use hyper::body::Bytes;
-use hyper::{header, Method, Response, StatusCode};
+use hyper::{header, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
 use regex::Regex;
-use tokio::io::AsyncReadExt; // for read_to_end()
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
 use crate::{
+    autoindex,
+    conditional::{self, CacheRules, DigestCache, EtagMode, Validators},
     context::Context,
-    types::{HttpRequest, HttpResonse},
+    encoding::{self, Encoding},
+    file_cache::FileCache,
+    range::{self, Multipart, Ranges},
+    response,
+    types::{HttpBody, HttpRequest, HttpResonse},
     view::View,
 };
 
+/// Serves the files under `root` for the `file_path` capture of `re_path`.
 pub struct StaticFiles {
     root: String,
     re_path: Regex,
+    follow_symlinks: bool,
+    allow_hidden: bool,
+    etag: EtagMode,
+    cache_rules: CacheRules,
+    index_files: Vec<String>,
+    autoindex: bool,
+    precompressed: bool,
+    spa_fallback: Option<String>,
+    cache: Option<FileCache>,
+    digests: DigestCache,
 }
 
 impl StaticFiles {
     pub fn new(root: String, re_path: Regex) -> Self {
-        Self { root, re_path }
+        Self {
+            root,
+            re_path,
+            follow_symlinks: false,
+            allow_hidden: false,
+            etag: EtagMode::default(),
+            cache_rules: CacheRules::default(),
+            index_files: vec!["index.html".to_string()],
+            autoindex: false,
+            precompressed: false,
+            spa_fallback: None,
+            cache: None,
+            digests: DigestCache::default(),
+        }
+    }
+
+    /// Follows symlinks as long as their target is inside `root`. Disabled by default, any
+    /// symlink on the path is then answered with 404.
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    /// Serves files and directories whose name starts with a dot, like `.well-known`.
+    /// Disabled by default so `.git` or `.env` inside `root` are never exposed.
+    pub fn allow_hidden(mut self, enabled: bool) -> Self {
+        self.allow_hidden = enabled;
+        self
+    }
+
+    pub fn etag(mut self, mode: EtagMode) -> Self {
+        self.etag = mode;
+        self
+    }
+
+    /// `Cache-Control` for files whose path relative to `root` matches `pattern`, e.g.
+    /// `public, max-age=31536000, immutable` for `\.[0-9a-f]{8}\.(js|css)$`. Rules are tried
+    /// in the order they were added.
+    pub fn cache_control(mut self, pattern: Regex, value: &str) -> Self {
+        self.cache_rules.add_pattern(pattern, value);
+        self
+    }
+
+    /// `Cache-Control` for files with the given extension.
+    pub fn cache_control_extension(mut self, extension: &str, value: &str) -> Self {
+        self.cache_rules.add_extension(extension, value);
+        self
+    }
+
+    /// `Cache-Control` for files no rule matched, none is sent by default.
+    pub fn default_cache_control(mut self, value: &str) -> Self {
+        self.cache_rules.set_default(value);
+        self
+    }
+
+    /// Files served for a directory request, tried in order. `index.html` by default, an empty
+    /// list disables index files.
+    pub fn index_files(mut self, names: &[&str]) -> Self {
+        self.index_files = names.iter().map(|v| v.to_string()).collect();
+        self
+    }
+
+    /// Lists directories without an index file as HTML, or JSON for clients that ask for it.
+    /// Disabled by default, those directories are answered with 404.
+    pub fn autoindex(mut self, enabled: bool) -> Self {
+        self.autoindex = enabled;
+        self
+    }
+
+    /// Sends `app.js.br` or `app.js.gz` instead of `app.js` when they exist next to it and the
+    /// client accepts that encoding. Brotli wins over gzip unless `Accept-Encoding` prefers
+    /// gzip with a higher q-value.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Serves `index` (relative to `root`) for unknown paths that look like client-side routes
+    /// of a single-page app: GET requests accepting `text/html` whose last segment has no file
+    /// extension. Missing assets like `app.js` still get a 404.
+    pub fn spa_fallback(mut self, index: &str) -> Self {
+        self.spa_fallback = Some(index.to_string());
+        self
+    }
+
+    /// Keeps small, frequently requested files in memory instead of reading them from disk on
+    /// every request. A [`FileCache`] can be shared by several views.
+    pub fn cache(mut self, cache: FileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Maps the captured request path to a file under `root`. None when it doesn't exist or
+    /// isn't allowed, both are answered with the same 404.
+    pub(crate) async fn resolve(&self, raw: &str) -> Option<PathBuf> {
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        let relative = sanitize_path(raw, self.allow_hidden)?;
+        let mut path = root.clone();
+        for segment in relative.iter() {
+            path.push(segment);
+            let metadata = tokio::fs::symlink_metadata(&path).await.ok()?;
+            if metadata.file_type().is_symlink() && !self.follow_symlinks {
+                return None;
+            }
+        }
+        // Catches symlinks pointing out of root.
+        let path = tokio::fs::canonicalize(&path).await.ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        Some(path)
+    }
+}
+
+// Decodes the path and checks it segment by segment without touching the file system.
+pub(crate) fn sanitize_path(raw: &str, allow_hidden: bool) -> Option<PathBuf> {
+    let decoded = percent_decode_str(raw).decode_utf8().ok()?;
+    // Backslashes are separators on Windows, NUL truncates paths in the OS.
+    if decoded.contains(['\\', '\0']) {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            s if s.starts_with('.') && !allow_hidden => return None,
+            s => relative.push(s),
+        }
+    }
+    // A segment like "C:" could still make the path absolute on Windows.
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(relative)
+}
+
+// The `/` separated form cache rules and embedded files are matched against.
+pub(crate) fn relative_string(relative: &Path) -> String {
+    relative
+        .iter()
+        .map(|v| v.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Where the bytes of a served file come from.
+pub(crate) enum Content {
+    File(File, PathBuf), // the path is opened again for each part of a multipart response
+    Memory(Bytes),
+}
+
+impl Content {
+    fn full(self) -> HttpBody {
+        match self {
+            Content::File(file, _) => response::reader_body(file),
+            Content::Memory(bytes) => response::full_body(bytes),
+        }
+    }
+
+    // Only the bytes of `range` are read from a file.
+    async fn range(self, range: &Range<u64>) -> anyhow::Result<HttpBody> {
+        match self {
+            Content::File(mut file, _) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(response::reader_body(file.take(range.end - range.start)))
+            }
+            Content::Memory(bytes) => Ok(response::full_body(
+                bytes.slice(range.start as usize..range.end as usize),
+            )),
+        }
+    }
+
+    fn multipart(self, multipart: Multipart) -> HttpBody {
+        let trailer = multipart.trailer;
+        match self {
+            Content::File(_, path) => {
+                let parts = stream::iter(multipart.parts).then(move |(part_header, range)| {
+                    let path = path.clone();
+                    async move {
+                        let mut file = File::open(&path).await?;
+                        file.seek(SeekFrom::Start(range.start)).await?;
+                        let data = ReaderStream::new(file.take(range.end - range.start));
+                        Ok::<_, io::Error>(stream::once(async { Ok(part_header) }).chain(data))
+                    }
+                });
+                let body = parts
+                    .try_flatten()
+                    .chain(stream::once(async { Ok(trailer) }));
+                response::stream_body(body)
+            }
+            Content::Memory(bytes) => {
+                let mut chunks = Vec::new();
+                for (part_header, range) in multipart.parts {
+                    chunks.push(part_header);
+                    chunks.push(bytes.slice(range.start as usize..range.end as usize));
+                }
+                chunks.push(trailer);
+                response::stream_body(stream::iter(chunks).map(Ok::<_, io::Error>))
+            }
+        }
+    }
+}
+
+/// The response for a GET of a representation of `len` bytes: 304 when the client's copy is
+/// still valid, otherwise 200, 206 or 416 depending on `Range`.
+pub(crate) async fn representation_response(
+    req: &HttpRequest,
+    content: Content,
+    len: u64,
+    validators: &Validators,
+    mime: &str,
+) -> anyhow::Result<HttpResonse> {
+    if validators.is_not_modified(req.headers()) {
+        let mut r = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(response::empty_body())?;
+        validators.apply(r.headers_mut())?;
+        return Ok(r);
+    }
+    let mut r = match range::requested(req, len, validators) {
+        Ranges::Full => {
+            let mut r = Response::builder()
+                .status(StatusCode::OK)
+                .body(content.full())?;
+            r.headers_mut().insert(header::CONTENT_LENGTH, len.into());
+            r
+        }
+        Ranges::Unsatisfiable => {
+            let mut r = Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(response::empty_body())?;
+            r.headers_mut().insert(
+                header::CONTENT_RANGE,
+                range::unsatisfied_range(len).parse()?,
+            );
+            r
+        }
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            let mut r = Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(content.range(range).await?)?;
+            let headers = r.headers_mut();
+            headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+            headers.insert(
+                header::CONTENT_RANGE,
+                range::content_range(range, len).parse()?,
+            );
+            r
+        }
+        Ranges::Partial(ranges) => {
+            let multipart = Multipart::new(ranges, len, mime);
+            let content_type = multipart.content_type();
+            let content_length = multipart.content_length();
+            let mut r = Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(content.multipart(multipart))?;
+            let headers = r.headers_mut();
+            headers.insert(header::CONTENT_TYPE, content_type.parse()?);
+            headers.insert(header::CONTENT_LENGTH, content_length.into());
+            r
+        }
+    };
+    if !r.headers().contains_key(header::CONTENT_TYPE)
+        && r.status() != StatusCode::RANGE_NOT_SATISFIABLE
+    {
+        r.headers_mut().insert(header::CONTENT_TYPE, mime.parse()?);
     }
+    r.headers_mut()
+        .insert(header::ACCEPT_RANGES, "bytes".parse()?);
+    validators.apply(r.headers_mut())?;
+    Ok(r)
+}
+
+pub(crate) fn is_client_route(req: &HttpRequest, raw: &str) -> bool {
+    let accepts_html = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("text/html"));
+    let last_segment = raw.rsplit('/').next().unwrap_or("");
+    req.method() == Method::GET && accepts_html && !last_segment.contains('.')
+}
+
+// Relative links in an index page only work when the directory URL ends with a slash.
+// `prefix` is the path a proxy stripped in front of us, see `Context::path_prefix`.
+pub(crate) fn redirect_to_directory<B>(
+    req: &Request<B>,
+    prefix: &str,
+) -> anyhow::Result<HttpResonse> {
+    // Browsers read a Location starting with `//` or `/\` as another host.
+    let path = format!("/{}", req.uri().path().trim_start_matches(['/', '\\']));
+    let location = match req.uri().query() {
+        Some(query) => format!("{}{}/?{}", prefix, path, query),
+        None => format!("{}{}/", prefix, path),
+    };
+    let mut r = Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .body(response::empty_body())?;
+    r.headers_mut().insert(header::LOCATION, location.parse()?);
+    Ok(r)
+}
+
+pub(crate) fn not_found() -> anyhow::Result<HttpResonse> {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(response::full_body("Not found"))?)
 }
 
 #[async_trait]
@@ -38,23 +372,498 @@ impl View for StaticFiles {
             .ok_or(anyhow!("no view_args"))?
             .get("file_path")
             .ok_or(anyhow!("no file_path"))?;
-        let file_path = format!("{}/{}", self.root, file_path);
-        let file_path = std::path::Path::new(&file_path);
-        if !file_path.exists() {
-            return Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Full::new(Bytes::from("Not found")))?);
-        }
-
-        let mut file = tokio::fs::File::open(file_path).await?;
-        let mut buf: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buf).await?;
-        let mime = mime_guess::from_path(file_path).first_or_octet_stream();
-        let mut r = Response::builder()
-            .status(StatusCode::OK)
-            .body(Full::new(Bytes::from(buf)))?;
-        r.headers_mut()
-            .insert(header::CONTENT_TYPE, (&mime.to_string()).parse()?);
+        let mut path = match self.resolve(file_path).await {
+            Some(v) => v,
+            None => return self.not_found_or_fallback(req, file_path).await,
+        };
+        let mut relative = file_path.clone();
+        if tokio::fs::metadata(&path).await?.is_dir() {
+            if !req.uri().path().ends_with('/') {
+                return redirect_to_directory(req, ctx.path_prefix());
+            }
+            match self.find_index(file_path).await {
+                Some((index, index_relative)) => {
+                    path = index;
+                    relative = index_relative;
+                }
+                None if self.autoindex => {
+                    let root = tokio::fs::canonicalize(&self.root).await?;
+                    let options = autoindex::Options {
+                        root: &root,
+                        prefix: ctx.path_prefix(),
+                        allow_hidden: self.allow_hidden,
+                        follow_symlinks: self.follow_symlinks,
+                    };
+                    return autoindex::render(req, &path, &options).await;
+                }
+                None => return not_found(),
+            }
+        }
+        self.serve_file(req, path, &relative).await
+    }
+}
+
+impl StaticFiles {
+    async fn not_found_or_fallback(
+        &self,
+        req: &HttpRequest,
+        raw: &str,
+    ) -> anyhow::Result<HttpResonse> {
+        let index = match self.spa_fallback {
+            Some(ref v) if is_client_route(req, raw) => v,
+            _ => return not_found(),
+        };
+        match self.resolve(index).await {
+            Some(path) if path.is_file() => self.serve_file(req, path, index).await,
+            _ => not_found(),
+        }
+    }
+
+    async fn find_index(&self, dir: &str) -> Option<(PathBuf, String)> {
+        for name in self.index_files.iter() {
+            let relative = format!("{}/{}", dir.trim_end_matches('/'), name);
+            if let Some(path) = self.resolve(&relative).await {
+                if path.is_file() {
+                    return Some((path, relative));
+                }
+            }
+        }
+        None
+    }
+
+    // `raw_relative` is the path as captured from the request, it's only used to pick the
+    // Cache-Control rule.
+    async fn serve_file(
+        &self,
+        req: &HttpRequest,
+        path: PathBuf,
+        raw_relative: &str,
+    ) -> anyhow::Result<HttpResonse> {
+        let relative = sanitize_path(raw_relative, self.allow_hidden).unwrap_or_default();
+        let relative = relative_string(&relative);
+
+        // The type of the original file, also when a compressed sibling is sent.
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        let (path, encoding) = match self.precompressed_sibling(req, raw_relative).await {
+            Some((sibling, encoding)) => (sibling, Some(encoding)),
+            None => (path, None),
+        };
+
+        let cached = match self.cache {
+            Some(ref cache) => cache.get(&path, self.etag == EtagMode::ContentHash).await?,
+            None => None,
+        };
+        let (content, len, modified, content_etag) = match cached {
+            Some(v) => {
+                let len = v.bytes.len() as u64;
+                (Content::Memory(v.bytes), len, v.modified, v.content_etag)
+            }
+            None => {
+                let file = File::open(&path).await?;
+                let metadata = file.metadata().await?;
+                let modified = metadata.modified().ok();
+                (
+                    Content::File(file, path.clone()),
+                    metadata.len(),
+                    modified,
+                    None,
+                )
+            }
+        };
+        let etag = match self.etag {
+            EtagMode::Metadata => Some(conditional::metadata_etag(len, modified)),
+            EtagMode::ContentHash => match content_etag {
+                Some(v) => Some(v),
+                None => Some(self.digests.content_etag(&path, len, modified).await?),
+            },
+            EtagMode::Disabled => None,
+        };
+        // `gzip -k` keeps the mtime, the size alone may not tell the representations apart.
+        let etag = match encoding {
+            Some(encoding) => {
+                etag.map(|v| format!("{}-{}\"", v.trim_end_matches('"'), encoding.name()))
+            }
+            None => etag,
+        };
+        let validators = Validators {
+            etag,
+            last_modified: modified,
+        };
+
+        let mut r = representation_response(req, content, len, &validators, mime.as_ref()).await?;
+        if let Some(value) = self.cache_rules.get(&relative) {
+            r.headers_mut()
+                .insert(header::CACHE_CONTROL, value.parse()?);
+        }
+        if self.precompressed {
+            r.headers_mut()
+                .insert(header::VARY, "accept-encoding".parse()?);
+        }
+        if let Some(encoding) = encoding {
+            r.headers_mut()
+                .insert(header::CONTENT_ENCODING, encoding.name().parse()?);
+        }
         Ok(r)
     }
+
+    // Goes through `resolve` like the original file, so the same rules apply to the sibling.
+    async fn precompressed_sibling(
+        &self,
+        req: &HttpRequest,
+        raw_relative: &str,
+    ) -> Option<(PathBuf, Encoding)> {
+        if !self.precompressed || encoding::preferred(req, &Encoding::PRECOMPRESSED).is_none() {
+            return None;
+        }
+        let mut available = Vec::new();
+        for encoding in Encoding::PRECOMPRESSED {
+            let sibling = format!("{}.{}", raw_relative, encoding.extension());
+            if let Some(path) = self.resolve(&sibling).await {
+                if path.is_file() {
+                    available.push((path, encoding));
+                }
+            }
+        }
+        let encodings: Vec<Encoding> = available.iter().map(|(_, v)| *v).collect();
+        let preferred = encoding::preferred(req, &encodings)?;
+        available.into_iter().find(|(_, v)| *v == preferred)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{request, RawResponse, TestServer};
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+    }
+
+    impl Fixture {
+        // <tmp>/root/{index.html, css/app.css, .env, .well-known/security.txt}
+        // <tmp>/secret.txt
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().join("root");
+            std::fs::create_dir_all(root.join("css")).unwrap();
+            std::fs::create_dir_all(root.join(".well-known")).unwrap();
+            std::fs::write(root.join("index.html"), "index").unwrap();
+            std::fs::write(root.join("css/app.css"), "css").unwrap();
+            std::fs::write(root.join(".env"), "SECRET=1").unwrap();
+            std::fs::write(root.join(".well-known/security.txt"), "sec").unwrap();
+            std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+            Fixture { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.path().join("root")
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(
+                self.root().to_string_lossy().to_string(),
+                Regex::new("^/static/(?P<file_path>.*)$").unwrap(),
+            )
+        }
+    }
+
+    async fn resolves(files: &StaticFiles, raw: &str) -> bool {
+        files.resolve(raw).await.is_some()
+    }
+
+    async fn serve(files: StaticFiles) -> std::net::SocketAddr {
+        let mut app = crate::SimpleApi::new();
+        app.add_route(files);
+        TestServer::start(app).await.addr
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str, headers: &str) -> RawResponse {
+        request(addr, format!("GET {} HTTP/1.1{}", path, headers), b"").await
+    }
+
+    #[tokio::test]
+    async fn serves_files_under_root() {
+        let f = Fixture::new();
+        let files = f.files();
+        let expected = f.root().join("css/app.css").canonicalize().unwrap();
+        assert_eq!(files.resolve("css/app.css").await, Some(expected));
+        assert!(resolves(&files, "index.html").await);
+        assert!(resolves(&files, "./css//app.css").await);
+        assert!(resolves(&files, "css%2Fapp.css").await);
+        assert!(!resolves(&files, "missing.html").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_parent_segments() {
+        let f = Fixture::new();
+        let files = f.files();
+        for raw in [
+            "../secret.txt",
+            "css/../../secret.txt",
+            "css/../index.html",
+            "..",
+            "/../secret.txt",
+        ] {
+            assert!(!resolves(&files, raw).await, "{}", raw);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_encoded_traversal() {
+        let f = Fixture::new();
+        let files = f.files();
+        for raw in [
+            "%2e%2e/secret.txt",
+            "%2E%2E%2Fsecret.txt",
+            "..%2fsecret.txt",
+            "css%2f..%2f..%2fsecret.txt",
+            "..%5csecret.txt",
+            "css\\..\\..\\secret.txt",
+            "index.html%00.png",
+            "%c0%ae%c0%ae/secret.txt", // overlong UTF-8
+            "%252e%252e/secret.txt",   // only decoded once
+        ] {
+            assert!(!resolves(&files, raw).await, "{}", raw);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_absolute_paths() {
+        let f = Fixture::new();
+        let files = f.files();
+        let secret = f.dir.path().join("secret.txt");
+        let secret = secret.to_string_lossy();
+        assert!(!resolves(&files, &secret).await);
+        assert!(!resolves(&files, "/etc/passwd").await);
+    }
+
+    #[tokio::test]
+    async fn hidden_files() {
+        let f = Fixture::new();
+        assert!(!resolves(&f.files(), ".env").await);
+        assert!(!resolves(&f.files(), "%2eenv").await);
+        assert!(!resolves(&f.files(), ".well-known/security.txt").await);
+
+        let files = f.files().allow_hidden(true);
+        assert!(resolves(&files, ".env").await);
+        assert!(resolves(&files, ".well-known/security.txt").await);
+        assert!(!resolves(&files, "../secret.txt").await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let f = Fixture::new();
+        symlink(f.root().join("index.html"), f.root().join("inside.html")).unwrap();
+        symlink(
+            f.dir.path().join("secret.txt"),
+            f.root().join("outside.txt"),
+        )
+        .unwrap();
+        symlink(f.dir.path(), f.root().join("up")).unwrap();
+
+        let files = f.files();
+        assert!(!resolves(&files, "inside.html").await);
+        assert!(!resolves(&files, "outside.txt").await);
+        assert!(!resolves(&files, "up/secret.txt").await);
+
+        let files = f.files().follow_symlinks(true);
+        assert!(resolves(&files, "inside.html").await);
+        assert!(!resolves(&files, "outside.txt").await);
+        assert!(!resolves(&files, "up/secret.txt").await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_root() {
+        use std::os::unix::fs::symlink;
+
+        let f = Fixture::new();
+        let link = f.dir.path().join("root-link");
+        symlink(f.root(), &link).unwrap();
+        let files = StaticFiles::new(
+            link.to_string_lossy().to_string(),
+            Regex::new("^/static/(?P<file_path>.*)$").unwrap(),
+        );
+        assert!(resolves(&files, "css/app.css").await);
+        assert!(!resolves(&files, "../secret.txt").await);
+    }
+
+    // css/app.css with .br and .gz siblings.
+    fn precompressed_fixture() -> Fixture {
+        let f = Fixture::new();
+        std::fs::write(f.root().join("css/app.css.br"), "br").unwrap();
+        std::fs::write(f.root().join("css/app.css.gz"), "gz").unwrap();
+        f
+    }
+
+    #[tokio::test]
+    async fn serves_precompressed_siblings() {
+        let f = precompressed_fixture();
+        let addr = serve(f.files().precompressed(true)).await;
+
+        let res = get(addr, "/static/css/app.css", "\r\nAccept-Encoding: gzip, br").await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"br");
+        assert_eq!(res.header("content-encoding"), Some("br"));
+        assert_eq!(res.header("content-type"), Some("text/css"));
+        assert_eq!(res.header("vary"), Some("accept-encoding"));
+        let br_etag = res.header("etag").unwrap().to_string();
+        assert!(br_etag.ends_with("-br\""), "{}", br_etag);
+
+        let res = get(addr, "/static/css/app.css", "\r\nAccept-Encoding: gzip").await;
+        assert_eq!(res.body, b"gz");
+        assert_eq!(res.header("content-encoding"), Some("gzip"));
+        assert_eq!(res.header("content-type"), Some("text/css"));
+        let gz_etag = res.header("etag").unwrap().to_string();
+        assert!(gz_etag.ends_with("-gzip\""), "{}", gz_etag);
+
+        let res = get(addr, "/static/css/app.css", "").await;
+        assert_eq!(res.body, b"css");
+        assert_eq!(res.header("content-encoding"), None);
+        assert_eq!(res.header("vary"), Some("accept-encoding"));
+        let etag = res.header("etag").unwrap();
+        assert!(etag != br_etag && etag != gz_etag);
+
+        // An ETag of one representation doesn't validate another.
+        let headers = format!("\r\nAccept-Encoding: br\r\nIf-None-Match: {}", br_etag);
+        assert_eq!(get(addr, "/static/css/app.css", &headers).await.status, 304);
+        let headers = format!("\r\nAccept-Encoding: gzip\r\nIf-None-Match: {}", br_etag);
+        assert_eq!(get(addr, "/static/css/app.css", &headers).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn q_values_pick_the_sibling() {
+        let f = precompressed_fixture();
+        let addr = serve(f.files().precompressed(true)).await;
+        let cases = [
+            ("gzip;q=1, br;q=0.5", "gzip"),
+            ("br;q=0.5, gzip;q=0.8", "gzip"),
+            ("br, gzip", "br"),
+            ("*", "br"),
+            ("br;q=0, gzip", "gzip"),
+        ];
+        for (accept, expected) in cases {
+            let headers = format!("\r\nAccept-Encoding: {}", accept);
+            let res = get(addr, "/static/css/app.css", &headers).await;
+            assert_eq!(res.header("content-encoding"), Some(expected), "{}", accept);
+        }
+        let res = get(
+            addr,
+            "/static/css/app.css",
+            "\r\nAccept-Encoding: br;q=0, gzip;q=0",
+        )
+        .await;
+        assert_eq!(res.body, b"css");
+        assert_eq!(res.header("content-encoding"), None);
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_a_sibling() {
+        let f = precompressed_fixture();
+        std::fs::remove_file(f.root().join("css/app.css.br")).unwrap();
+        let addr = serve(f.files().precompressed(true)).await;
+
+        let res = get(addr, "/static/css/app.css", "\r\nAccept-Encoding: br").await;
+        assert_eq!(res.body, b"css");
+        assert_eq!(res.header("content-encoding"), None);
+        let res = get(addr, "/static/css/app.css", "\r\nAccept-Encoding: br, gzip").await;
+        assert_eq!(res.body, b"gz");
+        let res = get(addr, "/static/index.html", "\r\nAccept-Encoding: br, gzip").await;
+        assert_eq!(res.body, b"index");
+        assert_eq!(res.header("content-type"), Some("text/html"));
+    }
+
+    #[tokio::test]
+    async fn ignores_siblings_unless_enabled() {
+        let f = precompressed_fixture();
+        let addr = serve(f.files()).await;
+        let res = get(addr, "/static/css/app.css", "\r\nAccept-Encoding: br, gzip").await;
+        assert_eq!(res.body, b"css");
+        assert_eq!(res.header("content-encoding"), None);
+        assert_eq!(res.header("vary"), None);
+    }
+
+    #[tokio::test]
+    async fn spa_fallback_serves_index_for_client_routes() {
+        let f = Fixture::new();
+        let addr = serve(f.files().spa_fallback("index.html")).await;
+        let html = "\r\nAccept: text/html,application/xhtml+xml,*/*;q=0.8";
+
+        for path in ["/static/app/settings", "/static/app", "/static/users/42/"] {
+            let res = get(addr, path, html).await;
+            assert_eq!(res.status, 200, "{}", path);
+            assert_eq!(res.body, b"index", "{}", path);
+            assert_eq!(res.header("content-type"), Some("text/html"));
+        }
+        // Existing files are still served as themselves.
+        assert_eq!(get(addr, "/static/css/app.css", html).await.body, b"css");
+    }
+
+    #[tokio::test]
+    async fn spa_fallback_keeps_404_for_assets_and_other_requests() {
+        let f = Fixture::new();
+        let addr = serve(f.files().spa_fallback("index.html")).await;
+        let html = "\r\nAccept: text/html";
+
+        for path in [
+            "/static/app.js",
+            "/static/logo.png",
+            "/static/app/v1.2/main.css",
+        ] {
+            assert_eq!(get(addr, path, html).await.status, 404, "{}", path);
+        }
+        assert_eq!(get(addr, "/static/app/settings", "").await.status, 404);
+        let json = "\r\nAccept: application/json";
+        assert_eq!(get(addr, "/static/app/settings", json).await.status, 404);
+        let post = request(
+            addr,
+            "POST /static/app/settings HTTP/1.1\r\nAccept: text/html",
+            b"",
+        )
+        .await;
+        assert_eq!(post.status, 404);
+
+        // Without the option every unknown path is a 404.
+        let addr = serve(f.files()).await;
+        assert_eq!(get(addr, "/static/app/settings", html).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn spa_fallback_to_missing_index_is_404() {
+        let f = Fixture::new();
+        let addr = serve(f.files().spa_fallback("missing.html")).await;
+        let res = get(addr, "/static/app/settings", "\r\nAccept: text/html").await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn directory_redirect_keeps_prefix_and_query() {
+        let req = Request::get("/static/css?sort=size").body(()).unwrap();
+        let r = redirect_to_directory(&req, "").unwrap();
+        assert_eq!(r.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(r.headers()[header::LOCATION], "/static/css/?sort=size");
+
+        let req = Request::get("/static/css").body(()).unwrap();
+        let r = redirect_to_directory(&req, "/api").unwrap();
+        assert_eq!(r.headers()[header::LOCATION], "/api/static/css/");
+    }
+
+    #[test]
+    fn directory_redirect_stays_on_this_host() {
+        for path in ["//images", "///images", "/%5Cimages"] {
+            let req = Request::get(path).body(()).unwrap();
+            let r = redirect_to_directory(&req, "").unwrap();
+            let location = r.headers()[header::LOCATION].to_str().unwrap();
+            assert!(location.starts_with('/'), "{}", location);
+            assert!(!location.starts_with("//"), "{}", location);
+        }
+        let req = Request::get("//images").body(()).unwrap();
+        let r = redirect_to_directory(&req, "").unwrap();
+        assert_eq!(r.headers()[header::LOCATION], "/images/");
+        let r = redirect_to_directory(&req, "/api").unwrap();
+        assert_eq!(r.headers()[header::LOCATION], "/api/images/");
+    }
 }
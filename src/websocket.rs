@@ -0,0 +1,377 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use hyper::{header, Method, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use regex::Regex;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig as WsConfig};
+use tokio_tungstenite::WebSocketStream;
+
+pub use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    connection::ConnectionInfo,
+    context::Context,
+    response,
+    server::Drain,
+    session::SessionProvider,
+    types::{HttpRequest, HttpResonse, State, ViewPathArgs},
+    view::View,
+};
+
+type Stream = WebSocketStream<TokioIo<Upgraded>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct WebSocketConfig {
+    max_frame_size: Option<usize>,
+    max_message_size: Option<usize>,
+    idle_timeout: Option<Duration>,
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Closes the socket when [`WebSocket::recv`] sees no message for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    fn protocol_config(&self) -> WsConfig {
+        let mut config = WsConfig::default();
+        if self.max_frame_size.is_some() {
+            config.max_frame_size = self.max_frame_size;
+        }
+        if self.max_message_size.is_some() {
+            config.max_message_size = self.max_message_size;
+        }
+        config
+    }
+}
+
+/// What the socket handler gets to see of the request that opened it.
+pub struct WebSocketContext {
+    /// A copy of the session data taken during the handshake. Changes to it are never saved,
+    /// the response that would carry the session cookie is already sent. Keep anything that
+    /// must outlive the socket in `state` or write it through `session_provider` yourself.
+    pub session: Option<Value>,
+    pub session_provider: Option<Arc<dyn SessionProvider>>,
+    pub state: State,
+    pub view_args: Option<ViewPathArgs>,
+    pub conn: Arc<ConnectionInfo>,
+}
+
+impl WebSocketContext {
+    pub fn get_state<T: 'static + Send + Sync>(&self) -> anyhow::Result<Arc<T>> {
+        self.state
+            .clone()
+            .downcast::<T>()
+            .map_err(|_| anyhow::anyhow!("cast failed"))
+    }
+}
+
+pub struct WebSocket {
+    inner: Stream,
+    idle_timeout: Option<Duration>,
+    drain: Option<Drain>,
+}
+
+impl WebSocket {
+    /// The next message, or None once the socket is closed. Pings are answered automatically
+    /// but still returned. When the server shuts down the socket is closed with 1001 (going
+    /// away) and None is returned.
+    pub async fn recv(&mut self) -> Option<anyhow::Result<Message>> {
+        let shutdown = async {
+            match self.drain {
+                Some(ref drain) => drain.shutdown().await,
+                None => std::future::pending::<()>().await,
+            }
+        };
+        let next = async {
+            match self.idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.inner.next()).await.ok(),
+                None => Some(self.inner.next().await),
+            }
+        };
+        let (code, reason) = tokio::select! {
+            next = next => match next {
+                Some(v) => return v.map(|v| v.map_err(anyhow::Error::from)),
+                None => (CloseCode::Away, "idle timeout"),
+            },
+            _ = shutdown => (CloseCode::Away, "server shutting down"),
+        };
+        let _ = self.close(code, reason).await;
+        None
+    }
+
+    pub async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        Ok(self.inner.send(message).await?)
+    }
+
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> anyhow::Result<()> {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        };
+        Ok(self.inner.close(Some(frame)).await?)
+    }
+
+    /// Splits into a sink and a stream that can be used from different tasks. The idle timeout
+    /// and the close on shutdown don't apply to the returned halves, the connection is only
+    /// dropped once the shutdown timeout runs out.
+    pub fn split(self) -> (SplitSink<Stream, Message>, SplitStream<Stream>) {
+        self.inner.split()
+    }
+}
+
+/// Accepts WebSocket upgrades on `re_path` and runs `handler` on each opened socket.
+pub struct WebSocketView<H> {
+    re_path: Regex,
+    handler: Arc<H>,
+    config: WebSocketConfig,
+}
+
+impl<H, F> WebSocketView<H>
+where
+    H: Fn(WebSocket, WebSocketContext) -> F + Send + Sync + 'static,
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    pub fn new(re_path: Regex, handler: H) -> Self {
+        WebSocketView {
+            re_path,
+            handler: Arc::new(handler),
+            config: WebSocketConfig::default(),
+        }
+    }
+
+    pub fn config(mut self, config: WebSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+fn header_contains(req: &HttpRequest, name: header::HeaderName, token: &str) -> bool {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+fn bad_request(msg: &str) -> anyhow::Result<HttpResonse> {
+    response::build_response(msg.to_string(), StatusCode::BAD_REQUEST, "text/plain")
+}
+
+#[async_trait]
+impl<H, F> View for WebSocketView<H>
+where
+    H: Fn(WebSocket, WebSocketContext) -> F + Send + Sync + 'static,
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    fn re_path(&self) -> Regex {
+        self.re_path.clone()
+    }
+    fn methods(&self) -> Vec<Method> {
+        vec![Method::GET]
+    }
+    async fn call(&self, req: &mut HttpRequest, ctx: &mut Context) -> anyhow::Result<HttpResonse> {
+        if req.method() != Method::GET
+            || !header_contains(req, header::CONNECTION, "upgrade")
+            || !header_contains(req, header::UPGRADE, "websocket")
+        {
+            return bad_request("Expected a WebSocket upgrade request");
+        }
+        if !header_contains(req, header::SEC_WEBSOCKET_VERSION, "13") {
+            let mut r = bad_request("Unsupported WebSocket version")?;
+            r.headers_mut()
+                .insert(header::SEC_WEBSOCKET_VERSION, "13".parse()?);
+            return Ok(r);
+        }
+        let accept = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+            Some(key) => derive_accept_key(key.as_bytes()),
+            None => return bad_request("Missing Sec-WebSocket-Key"),
+        };
+
+        let ws_ctx = WebSocketContext {
+            session: ctx.session.as_ref().map(|v| v.value().clone()),
+            session_provider: ctx.session_provider.clone(),
+            state: ctx.state.clone(),
+            view_args: ctx.view_args.clone(),
+            conn: ctx.conn.clone(),
+        };
+        let drain = req.extensions().get::<Drain>().cloned();
+        let on_upgrade = hyper::upgrade::on(req);
+        let handler = self.handler.clone();
+        let protocol_config = self.config.protocol_config();
+        let idle_timeout = self.config.idle_timeout;
+        tokio::task::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("WebSocket upgrade failed: {:?}", e);
+                    return;
+                }
+            };
+            let inner = WebSocketStream::from_raw_socket(
+                TokioIo::new(upgraded),
+                Role::Server,
+                Some(protocol_config),
+            )
+            .await;
+            let socket = WebSocket {
+                inner,
+                idle_timeout,
+                drain: drain.clone(),
+            };
+            let handled = handler(socket, ws_ctx);
+            // Holding on to the drain keeps the server waiting for this socket on shutdown.
+            let res = match drain {
+                Some(ref drain) => tokio::select! {
+                    res = handled => res,
+                    _ = drain.abort() => Ok(()),
+                },
+                None => handled.await,
+            };
+            if let Err(e) = res {
+                println!("WebSocket handler error: {:?}", e);
+            }
+        });
+
+        let mut r = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .body(response::full_body(""))?;
+        let headers = r.headers_mut();
+        headers.insert(header::CONNECTION, "upgrade".parse()?);
+        headers.insert(header::UPGRADE, "websocket".parse()?);
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept.parse()?);
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use crate::test_support::{read_head, RawResponse, TestServer};
+    use crate::SimpleApi;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    // Echoes text messages until the socket closes.
+    async fn serve() -> TestServer {
+        let mut app = SimpleApi::new();
+        app.set_server_config(ServerConfig::default().shutdown_timeout(Duration::from_secs(30)));
+        app.add_route(WebSocketView::new(
+            Regex::new("^/ws$").unwrap(),
+            |mut ws: WebSocket, _ctx| async move {
+                while let Some(msg) = ws.recv().await {
+                    if let Message::Text(text) = msg? {
+                        ws.send(Message::Text(text)).await?;
+                    }
+                }
+                Ok(())
+            },
+        ));
+        TestServer::start(app).await
+    }
+
+    // The response head to a GET /ws with `headers`, the connection is left open.
+    async fn handshake(addr: std::net::SocketAddr, headers: &str) -> RawResponse {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET /ws HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_head(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn accepts_handshake() {
+        let server = serve().await;
+        // The example from RFC 6455 section 1.3.
+        let res = handshake(
+            server.addr,
+            "Connection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        )
+        .await;
+        assert_eq!(res.status, 101, "{}", res.head);
+        assert_eq!(
+            res.header("sec-websocket-accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(res.header("upgrade"), Some("websocket"));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_handshakes() {
+        let server = serve().await;
+        let cases = [
+            "",
+            "Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+            "Connection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n",
+        ];
+        for headers in cases {
+            let res = handshake(server.addr, headers).await;
+            assert_eq!(res.status, 400, "{}", res.head);
+        }
+
+        let res = handshake(
+            server.addr,
+            "Connection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 8\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        )
+        .await;
+        assert_eq!(res.status, 400, "{}", res.head);
+        assert_eq!(res.header("sec-websocket-version"), Some("13"));
+    }
+
+    #[tokio::test]
+    async fn closes_sockets_on_shutdown() {
+        let mut server = serve().await;
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let url = format!("ws://{}/ws", server.addr);
+        let (mut client, response) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        assert_eq!(response.status().as_u16(), 101);
+        client.send(Message::Text("hi".into())).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Text("hi".into())
+        );
+
+        server.shutdown();
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap();
+        match closed {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        // Finishing the close handshake lets the server stop well before the shutdown timeout.
+        while client.next().await.is_some() {}
+        tokio::time::timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}